@@ -0,0 +1,146 @@
+//! Débito do tracker com muitos pedidos `File` (locate) em simultâneo,
+//! e o custo de codificar as respostas.
+//!
+//! `cargo bench --bench tracker`
+use criterion::{
+    criterion_group, criterion_main, BenchmarkId, Criterion, Throughput,
+};
+use local::codec::{Decode, Encode};
+use local::config::TrackerConfig;
+use local::file_meta::FileMeta;
+use local::fstp::{encode_message, Flag};
+use local::peers_with_blocks::PeersWithFile;
+use local::tracker_client::TrackerClient;
+use local::tracker_server::{RunningTracker, TrackerServer};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Nós com ficheiros, metade seeders e metade com parte dos blocos.
+const NODES: usize = 64;
+const FILES: usize = 16;
+const BLOCKS: u32 = 64;
+/// Clientes a fazer pedidos ao mesmo tempo.
+const CONCURRENCY: [usize; 4] = [1, 4, 16, 64];
+
+fn meta(file: usize, node: usize) -> FileMeta {
+    let mut meta =
+        FileMeta::full(format!("file{}.bin", file), 1 << 24, 1 << 18);
+    if node % 2 == 1 {
+        meta.has_full_file = false;
+        meta.blocks = (0..BLOCKS)
+            .map(|b| (b as usize + node).is_multiple_of(3))
+            .collect();
+    }
+    meta
+}
+
+// Tracker com `NODES` nós ligados; as ligações ficam abertas enquanto
+// durar o benchmark
+fn tracker() -> (RunningTracker, Vec<TrackerClient>) {
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let tracker = TrackerServer::bind(addr, TrackerConfig::default())
+        .unwrap()
+        .spawn();
+    let nodes = (0..NODES)
+        .map(|node| {
+            let mut client =
+                TrackerClient::connect(tracker.local_addr()).unwrap();
+            client.set_port(9000 + node as u16).unwrap();
+            let metas: Vec<FileMeta> =
+                (0..FILES).map(|file| meta(file, node)).collect();
+            client.announce(&metas).unwrap();
+            client
+        })
+        .collect();
+    // Os anúncios não têm resposta: espera que estejam todos registados
+    let started = Instant::now();
+    while tracker.state().holders("file0.bin").len() < NODES {
+        assert!(started.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(10));
+    }
+    (tracker, nodes)
+}
+
+fn locate(c: &mut Criterion) {
+    let (tracker, _nodes) = tracker();
+    let mut group = c.benchmark_group("locate");
+    for clients in CONCURRENCY {
+        let mut conns: Vec<TrackerClient> = (0..clients)
+            .map(|_| TrackerClient::connect(tracker.local_addr()).unwrap())
+            .collect();
+        // Cada iteração é um pedido de cada cliente
+        group.throughput(Throughput::Elements(clients as u64));
+        group.bench_function(BenchmarkId::from_parameter(clients), |b| {
+            b.iter_custom(|iters| {
+                let started = Instant::now();
+                thread::scope(|s| {
+                    for (i, conn) in conns.iter_mut().enumerate() {
+                        s.spawn(move || {
+                            for n in 0..iters as usize {
+                                let name =
+                                    format!("file{}.bin", (i + n) % FILES);
+                                let peers = conn.locate(&name).unwrap();
+                                assert!(!peers.peers_with_file.is_empty());
+                            }
+                        });
+                    }
+                });
+                started.elapsed()
+            })
+        });
+    }
+    group.finish();
+    tracker.shutdown().unwrap();
+}
+
+fn peers() -> PeersWithFile {
+    let mut peers = PeersWithFile::new(BLOCKS);
+    let addr = |i: usize| SocketAddr::from(([10, 0, 0, i as u8], 9000));
+    peers.peers_with_file = (0..16).map(addr).collect();
+    for b in 0..BLOCKS {
+        let holders: HashSet<SocketAddr> =
+            (0..4).map(|i| addr(16 + (b as usize + i) % 32)).collect();
+        peers.peers_with_blocks.insert(b, holders);
+    }
+    peers
+}
+
+fn codec(c: &mut Criterion) {
+    let peers = peers();
+    let bytes = peers.to_vec().unwrap();
+    let mut group = c.benchmark_group("codec");
+    group.throughput(Throughput::Bytes(bytes.len() as u64));
+    group
+        .bench_function("encode peers", |b| b.iter(|| peers.to_vec().unwrap()));
+    // Como o tracker: com o mesmo buffer, já com capacidade
+    let mut buf = Vec::new();
+    group.bench_function("encode peers, reused buffer", |b| {
+        b.iter(|| {
+            buf.clear();
+            encode_message(Flag::Ok, &peers, &mut buf).unwrap()
+        })
+    });
+    group.bench_function("decode peers", |b| {
+        b.iter(|| PeersWithFile::decode(&bytes).unwrap())
+    });
+
+    let metas: Vec<FileMeta> = (0..FILES).map(|file| meta(file, 1)).collect();
+    let len: usize = metas.iter().map(Encode::encoded_len).sum();
+    group.throughput(Throughput::Bytes(len as u64));
+    let mut buf = Vec::new();
+    group.bench_function("encode announce", |b| {
+        b.iter(|| {
+            buf.clear();
+            for meta in &metas {
+                meta.encode(&mut buf).unwrap();
+            }
+            buf.len()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, locate, codec);
+criterion_main!(benches);
@@ -1,173 +1,906 @@
 #![feature(let_chains)]
 
 use anyhow::{Context, bail};
-use local::file_meta::*;
-use local::fstp::*;
+use local::config::{parse_kib, NodeConfig, SharedRoot};
+use local::download::Downloader;
+use local::load::UploadStats;
 use local::peers_with_blocks::*;
-use bitvec::prelude::*;
-use std::collections::HashSet;
+use local::ratelimit::RateLimiter;
+use local::reliable::ReliableSocket;
+use local::store::{normalize_name, FileStore};
+use local::tracker_client::{TrackerClient, TrackerError};
+use local::transfer;
+use std::collections::HashMap;
 use std::env;
-use std::fs::{read_dir, File, ReadDir};
-use std::io::{Read, Write, stdin,stdout};
-use std::net::{TcpStream,Shutdown};
-use std::str::from_utf8;
+use std::fs::{self, create_dir_all, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write, stdin,stdout};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 // const CHUNK_BYTES:u16 = 1420; 
+/// De quanto em quanto tempo a carga do nó é enviada ao tracker.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Node {
+    store: Arc<FileStore>,
+    downloader: Downloader,
+    upload_limit: Arc<RateLimiter>,
+    download_limit: Arc<RateLimiter>,
+    udp: Arc<ReliableSocket>,
+    stats: Arc<UploadStats>,
+    config: NodeConfig,
+    // Porta onde os blocos são servidos
+    port: u16,
+}
+
+/// Códigos de saída dos subcomandos (os erros saem com 1).
+const EXIT_USAGE: i32 = 2;
+const EXIT_NOT_FOUND: i32 = 3;
+
+/// Socket de controlo do daemon, se não for indicado outro.
+const CONTROL_SOCKET: &str = "./node.sock";
+/// Saída do daemon, que não tem terminal.
+const DAEMON_LOG: &str = "./node.log";
+const DAEMON_START_TIMEOUT: Duration = Duration::from_secs(10);
+/// Tempo que um cliente do socket de controlo tem para mandar o comando.
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(5);
+
+const USAGE: &str = "\
+Usage: node <tracker ip:port> [command]
+       node ctl [--socket <path>] <shell command>
+
+Without a command the node shares its files and reads commands from stdin.
+Commands:
+  list                      files known to the tracker, one per line
+  locate <file>             peers with the file, tab-separated
+  get <file> [--out <dir>]  download the file and print its path
+  seed                      share the files until the tracker goes away
+  daemon [--socket <path>]  share the files in the background and take
+                            shell commands on a Unix socket (./node.sock)
+  ctl                       send a shell command to a running daemon";
 
 fn main() -> anyhow::Result<()> {
-    let mut stream = if let Some(tracker_addr) = env::args().nth(1){
-        TcpStream::connect(tracker_addr)    
-        .context("Can't connect to server")?
-    } else {
-        bail!("No tracker address specified (ip:port)")
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["ctl", ref command @ ..] => return ctl(command),
+        [_, "daemon", ref opts @ ..] => {
+            if opts.last() != Some(&"--foreground") {
+                return detach(&args, &control_socket(opts));
+            }
+            // Já é o processo em segundo plano
+            args.pop();
+        }
+        _ => {}
+    }
+    let Some((tracker_addr, command)) = args.split_first() else {
+        usage();
     };
+    let mut client = TrackerClient::connect(*tracker_addr)
+        .context("Can't connect to server")?;
+    let config = NodeConfig::load(Path::new("./node.config"))?;
 
-    contact_tracker(&mut stream)?;
+    match command[..] {
+        [] => {
+            let (tracker, node) = start(client, &config)?;
+            main_loop(&tracker, &node)
+        }
+        ["seed"] => {
+            let (tracker, _node) = start(client, &config)?;
+            // O tracker só escreve em resposta a pedidos: a leitura só
+            // acaba quando a ligação fecha
+            let mut probe = tracker.lock().unwrap().try_clone()?;
+            probe.wait_closed()?;
+            bail!("Tracker no longer reachable")
+        }
+        ["daemon", ref opts @ ..] => {
+            let socket = control_socket(opts);
+            let (tracker, node) = start(client, &config)?;
+            daemon(&tracker, &node, &socket)
+        }
+        ["list"] => {
+            for f in client.list()? {
+                println!("{}", f);
+            }
+            Ok(())
+        }
+        ["locate", f_name] => {
+            print_peers(&locate(&mut client, f_name)?);
+            Ok(())
+        }
+        ["get", f_name] => {
+            get(client, &config, f_name, config.shared_roots())
+        }
+        ["get", f_name, "--out", dir] => {
+            let out = SharedRoot::main(PathBuf::from(dir));
+            get(client, &config, f_name, vec![out])
+        }
+        _ => usage(),
+    }
+}
 
-    main_loop(&mut stream)?;
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(EXIT_USAGE);
+}
+
+fn not_found(f_name: &str) -> ! {
+    eprintln!("Unknown file: {}", f_name);
+    process::exit(EXIT_NOT_FOUND);
+}
+
+// Sai com EXIT_NOT_FOUND se o tracker não conhecer o ficheiro
+fn locate(
+    client: &mut TrackerClient,
+    f_name: &str,
+) -> anyhow::Result<PeersWithFile> {
+    match client.locate(f_name) {
+        Err(TrackerError::UnknownFile(_)) => not_found(f_name),
+        res => Ok(res?),
+    }
+}
+
+// Serve os ficheiros partilhados e anuncia-os ao tracker
+fn start(
+    client: TrackerClient,
+    config: &NodeConfig,
+) -> anyhow::Result<(Arc<Mutex<TrackerClient>>, Node)> {
+    // Carrega também os downloads incompletos, já reverificados
+    let store = Arc::new(FileStore::open_roots(config.shared_roots())?);
+    let upload_limit = Arc::new(RateLimiter::new(
+        config.upload_limit,
+        config.peer_upload_limit,
+    ));
+    let download_limit = Arc::new(RateLimiter::new(
+        config.download_limit,
+        config.peer_download_limit,
+    ));
+    // Os outros nós podem pedir blocos por TCP ou por UDP, na mesma
+    // porta (com a porta 0, a que o TCP receber)
+    let listener = TcpListener::bind(("0.0.0.0", config.port))
+        .context("Can't bind transfer port")?;
+    let port = listener.local_addr()?.port();
+    let udp = ReliableSocket::bind(("0.0.0.0", port), config.reliable())
+        .context("Can't bind UDP transfer port")?;
+    let stats = Arc::new(UploadStats::new());
+    let node = Node {
+        store: store.clone(),
+        downloader: Downloader::new(config, download_limit.clone()),
+        upload_limit: upload_limit.clone(),
+        download_limit,
+        udp: Arc::new(udp),
+        stats: stats.clone(),
+        config: config.clone(),
+        port,
+    };
+
+    let (udp_store, udp_limit) = (store.clone(), upload_limit.clone());
+    let udp_stats = stats.clone();
+    let tcp_stats = stats.clone();
+    thread::spawn(move || {
+        transfer::serve(listener, store, upload_limit, tcp_stats)
+    });
+    let udp = node.udp.clone();
+    thread::spawn(move || {
+        transfer::serve_udp(udp, udp_store, udp_limit, udp_stats)
+    });
+
+    // Partilhado com a thread que reporta a carga
+    let tracker = Arc::new(Mutex::new(client));
+    tracker.lock().unwrap().set_port(port)?;
+    tracker.lock().unwrap().announce(&node.store.metas())?;
+    let report_to = tracker.clone();
+    thread::spawn(move || report_load(&report_to, &stats));
 
+    Ok((tracker, node))
+}
+
+// `get`: descarrega o ficheiro para uma das `roots`, sem servir outros
+// nós
+fn get(
+    mut client: TrackerClient,
+    config: &NodeConfig,
+    f_name: &str,
+    roots: Vec<SharedRoot>,
+) -> anyhow::Result<()> {
+    let Some(f_name) = normalize_name(f_name) else {
+        bail!("Invalid file name: {}", f_name);
+    };
+    let f_name = f_name.as_str();
+    let dir = &roots[0].path;
+    create_dir_all(dir)
+        .with_context(|| format!("Can't create {}", dir.display()))?;
+    let store = FileStore::open_roots(roots)?;
+    if !store.meta(f_name).is_some_and(|m| m.has_full_file) {
+        let p_w_f = locate(&mut client, f_name)?;
+        let limiter = Arc::new(RateLimiter::new(
+            config.download_limit,
+            config.peer_download_limit,
+        ));
+        Downloader::new(config, limiter).download(&store, f_name, &p_w_f)?;
+    }
+    println!("{}", store.path(f_name).unwrap_or_default().display());
     Ok(())
 }
 
-fn main_loop(stream:&mut TcpStream) -> anyhow::Result<()> {
-    let mut files: HashSet<String> = HashSet::new();
-    loop {
-        let mut buf = [0u8;1000];
-        let mut raw_command = String::new();
-        stdout().write_all("Input command\n".as_bytes())?;
-        stdout().flush()?;
-        stdin().read_line(&mut raw_command)?;
-        let command = String::from(raw_command.to_lowercase().trim_end());
-
-        match command.as_str() {
-            "list" => {
-                let msg = FstpMessage{
-                    header: FstpHeader { flag: Flag::List, data_size:0 },
-                    data:None,
-                };
-                let msg_size = msg.as_bytes(&mut buf)?;
-                stream.write_all(&buf[..msg_size])?;
-                stream.flush()?;
-
-                if stream.read(&mut buf)? == 0 {
-                    bail!("Tracker no longer reachable");
-                } 
-                
-                let response = FstpMessage::from_bytes(&buf)?;
-                println!("resp:{:?}",response);
-                if let Some(data) = response.data {
-                    let list = from_utf8(data).unwrap().split(|c| c == ',');
-                    for f in list {
-                        files.insert(String::from(f));
-                    }
-                }
-                println!("files:{:#?}",files);
+// Uma linha por seeder e por bloco, com os campos separados por tabs
+fn print_peers(p_w_f: &PeersWithFile) {
+    println!("blocks\t{}", p_w_f.n_blocks);
+    let mut seeders: Vec<_> = p_w_f.peers_with_file.iter().collect();
+    seeders.sort();
+    for addr in seeders {
+        println!("seeder\t{}", addr);
+    }
+    let mut blocks: Vec<_> = p_w_f.peers_with_blocks.iter().collect();
+    blocks.sort_by_key(|(b, _)| **b);
+    for (b, addrs) in blocks {
+        let mut addrs: Vec<_> = addrs.iter().collect();
+        addrs.sort();
+        let addrs: Vec<String> = addrs.iter().map(|a| a.to_string()).collect();
+        println!("block\t{}\t{}", b, addrs.join(","));
+    }
+}
+
+/// Comandos da shell, com os argumentos, e o que fazem.
+const HELP: &[(&str, &str)] = &[
+    ("help", "this list"),
+    ("status", "state of the node"),
+    ("list", "files known to the tracker"),
+    ("info <file>", "local copy of a file"),
+    ("peers <file>", "peers the tracker gives for a file"),
+    ("get <file>", "download a file in the background"),
+    ("downloads", "downloads in progress"),
+    ("cancel <file>", "stop a download (get resumes it)"),
+    ("share <path>", "share a file from a shared root"),
+    ("unshare <file>", "stop sharing a file"),
+    ("roots", "shared directories"),
+    ("rescan <root>", "reread a shared root (- for the main one)"),
+    ("limit [upload|download] [global|peer] <KiB/s>", "rate limits"),
+    ("congestion", "UDP congestion state of each peer"),
+    ("exit | shutdown", "leave the network"),
+];
+
+/// O que fazer depois de um comando.
+enum Flow {
+    Continue,
+    Exit,
+}
+
+fn main_loop(tracker: &Mutex<TrackerClient>, node: &Node) -> anyhow::Result<()> {
+    println!("Type help for the list of commands");
+    // Os downloads correm em threads próprias, interrompidas à saída
+    thread::scope(|s| -> anyhow::Result<()> {
+        loop {
+            let mut line = String::new();
+            print!("> ");
+            stdout().flush()?;
+            if stdin().read_line(&mut line)? == 0 {
+                return leave(tracker, node);
             }
-            "file" => {
-                let mut f_name = String::new();
-                stdout().write_all("Input file name\n".as_bytes())?;
-                stdout().flush()?;
-                stdin().read_line(&mut f_name)?;                
-
-                if files.iter().any(|str| str == &f_name.trim_end()) {
-                    let msg = FstpMessage {
-                        header: FstpHeader { 
-                            flag: Flag::File,
-                            data_size:f_name.len() as u16
-                        },
-                        data: Some(f_name.as_bytes())
-                    };
-                    let msg_size = msg.as_bytes(&mut buf)?;
-                    stream.write_all(&mut buf[..msg_size])?;
-                    stream.flush()?;
-    
-                
-                    if stream.read(&mut buf)? == 0 {
-                        bail!("Server no longer reachable");
-                    } 
-                
-                    let resp = FstpMessage::from_bytes(&buf)?;
-                    println!("resp:{:?}",resp);
-                    if let Some(data) = resp.data {
-                        let peers_with_file = PeersWithFile::from_bytes(data)?;
-                        println!("p_w_f:{:?}",peers_with_file);
-                    }
-                }
+            match run_command(s, tracker, node, &line, &mut stdout()) {
+                Ok(Flow::Continue) => {}
+                Ok(Flow::Exit) => return leave(tracker, node),
+                Err(e) => println!("{}", e),
             }
-            "exit" => {
-                stream.shutdown(Shutdown::Both)?;
-                break;
+        }
+    })
+}
+
+// Uma linha da shell ou do socket de controlo; o resultado vai para `out`
+fn run_command<'s>(
+    s: &'s thread::Scope<'s, '_>,
+    tracker: &'s Mutex<TrackerClient>,
+    node: &'s Node,
+    line: &str,
+    out: &mut dyn Write,
+) -> anyhow::Result<Flow> {
+    let mut words = line.split_whitespace();
+    let Some(command) = words.next() else {
+        return Ok(Flow::Continue);
+    };
+    let args: Vec<&str> = words.collect();
+    match (command.to_lowercase().as_str(), &args[..]) {
+        ("help", []) => help(out)?,
+        ("status", []) => status(out, tracker, node)?,
+        ("list", []) => list(out, tracker, node)?,
+        ("info", [f_name]) => info(out, node, f_name)?,
+        ("peers" | "file", [f_name]) => peers(out, tracker, node, f_name)?,
+        ("get", [f_name]) => get_in_background(out, s, tracker, node, f_name)?,
+        ("downloads", []) => downloads(out, node)?,
+        ("cancel", [f_name]) => cancel(out, node, f_name)?,
+        ("share", [path]) => share(out, tracker, node, Path::new(path))?,
+        ("unshare", [f_name]) => unshare(out, tracker, node, f_name)?,
+        ("roots", []) => roots(out, node)?,
+        ("rescan", [root]) => rescan(out, tracker, node, root)?,
+        ("limit", args) => limit(out, node, args)?,
+        ("congestion", []) => congestion(out, node)?,
+        ("exit" | "shutdown", []) => return Ok(Flow::Exit),
+        _ => bail!("Invalid command: {} (try help)", line.trim()),
+    }
+    Ok(Flow::Continue)
+}
+
+// Interrompe os downloads e fecha a ligação ao tracker
+fn leave(tracker: &Mutex<TrackerClient>, node: &Node) -> anyhow::Result<()> {
+    for (name, _) in node.downloader.downloads() {
+        node.downloader.cancel(&name);
+    }
+    tracker.lock().unwrap().shutdown()?;
+    Ok(())
+}
+
+// `daemon`: sem terminal, os comandos da shell chegam pelo socket de
+// controlo, um por ligação
+fn daemon(
+    tracker: &Mutex<TrackerClient>,
+    node: &Node,
+    socket: &Path,
+) -> anyhow::Result<()> {
+    let listener = bind_control(socket)?;
+    println!("Listening for commands on {}", socket.display());
+    let res = thread::scope(|s| -> anyhow::Result<()> {
+        for conn in listener.incoming() {
+            let mut conn = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    println!("Control connection failed: {}", e);
+                    continue;
+                }
+            };
+            if let Flow::Exit = control(s, tracker, node, &mut conn) {
+                return leave(tracker, node);
             }
-            _=> println!("Invalid command: {}",command),
         }
+        Ok(())
+    });
+    let _ = fs::remove_file(socket);
+    res
+}
+
+fn bind_control(socket: &Path) -> anyhow::Result<UnixListener> {
+    if socket.exists() {
+        // Se ninguém aceitar ligações, é de um daemon que já terminou
+        if UnixStream::connect(socket).is_ok() {
+            bail!("A node is already listening on {}", socket.display());
+        }
+        fs::remove_file(socket)?;
+    }
+    UnixListener::bind(socket)
+        .with_context(|| format!("Can't bind {}", socket.display()))
+}
+
+// Resposta: `ok` ou `error` na primeira linha, seguido do resultado ou
+// da mensagem de erro
+fn control<'s>(
+    s: &'s thread::Scope<'s, '_>,
+    tracker: &'s Mutex<TrackerClient>,
+    node: &'s Node,
+    conn: &mut UnixStream,
+) -> Flow {
+    let mut line = String::new();
+    let _ = conn.set_read_timeout(Some(CONTROL_TIMEOUT));
+    if let Err(e) = BufReader::new(&*conn).read_line(&mut line) {
+        println!("Control connection failed: {}", e);
+        return Flow::Continue;
+    }
+    let mut out = Vec::new();
+    let (flow, resp) = match run_command(s, tracker, node, &line, &mut out) {
+        Ok(flow) => (flow, [b"ok\n".as_slice(), &out].concat()),
+        Err(e) => (Flow::Continue, format!("error\n{}\n", e).into_bytes()),
+    };
+    let _ = conn.write_all(&resp);
+    flow
+}
+
+// `--socket <path>` nas opções do daemon e do ctl
+fn control_socket(opts: &[&str]) -> PathBuf {
+    match opts {
+        [] => PathBuf::from(CONTROL_SOCKET),
+        ["--socket", path] => PathBuf::from(path),
+        _ => usage(),
+    }
+}
+
+// Volta a lançar o nó em segundo plano, sem terminal, e espera que o
+// socket de controlo esteja pronto
+fn detach(args: &[&str], socket: &Path) -> anyhow::Result<()> {
+    // Senão o socket do outro daemon parecia o do novo
+    if UnixStream::connect(socket).is_ok() {
+        bail!("A node is already listening on {}", socket.display());
+    }
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(DAEMON_LOG)
+        .with_context(|| format!("Can't open {}", DAEMON_LOG))?;
+    let mut child = Command::new(env::current_exe()?)
+        .args(args)
+        .arg("--foreground")
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .process_group(0)
+        .spawn()
+        .context("Can't start the daemon")?;
+    let deadline = Instant::now() + DAEMON_START_TIMEOUT;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            bail!("The daemon exited ({}), see {}", status, DAEMON_LOG);
+        }
+        if UnixStream::connect(socket).is_ok() {
+            println!("{}", child.id());
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    bail!("The daemon didn't open {}, see {}", socket.display(), DAEMON_LOG)
+}
+
+// `ctl`: manda um comando ao daemon e mostra a resposta
+fn ctl(args: &[&str]) -> anyhow::Result<()> {
+    let (socket, command) = match args {
+        ["--socket", path, command @ ..] => (Path::new(*path), command),
+        command => (Path::new(CONTROL_SOCKET), command),
+    };
+    if command.is_empty() {
+        usage();
+    }
+    let mut conn = UnixStream::connect(socket).with_context(|| {
+        format!("Can't reach the daemon at {}", socket.display())
+    })?;
+    conn.write_all(format!("{}\n", command.join(" ")).as_bytes())?;
+    let mut resp = String::new();
+    conn.read_to_string(&mut resp)?;
+    match resp.split_once('\n') {
+        Some(("ok", body)) => {
+            print!("{}", body);
+            Ok(())
+        }
+        Some(("error", msg)) => {
+            eprint!("{}", msg);
+            process::exit(1);
+        }
+        _ => bail!("Invalid response from the daemon"),
+    }
+}
+
+/// Tabela com as colunas alinhadas pela célula mais larga.
+fn print_table(
+    out: &mut dyn Write,
+    header: &[&str],
+    rows: &[Vec<String>],
+) -> io::Result<()> {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+    let header: Vec<String> = header.iter().map(|h| h.to_string()).collect();
+    for row in std::iter::once(&header).chain(rows) {
+        let padded: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{:<w$}", c, w = w))
+            .collect();
+        writeln!(out, "{}", padded.join("  ").trim_end())?;
+    }
+    Ok(())
+}
+
+/// Pares nome/valor, um por linha, com os valores alinhados.
+fn print_fields(
+    out: &mut dyn Write,
+    fields: &[(&str, String)],
+) -> io::Result<()> {
+    let width = fields.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+    for (k, v) in fields {
+        writeln!(out, "{:<w$}  {}", k, v, w = width)?;
     }
     Ok(())
 }
 
-fn contact_tracker(stream:&mut TcpStream) ->anyhow::Result<()> {
-    let files_meta = get_files_meta();
-    let mut fm_buf = [0u8;100];
-    let mut raw_data = [0u8;1000];
-    let mut data_size = 0;
-    let mut prev_ds = 0;
-
-    for f_m in files_meta {
-        let fm_size = f_m.as_bytes(&mut fm_buf).expect("Failed to serialize FM");
-        data_size+=fm_size;
-        raw_data[prev_ds..data_size].copy_from_slice(&fm_buf[..fm_size]);
-        prev_ds = data_size;
-    }
-    let msg = FstpMessage {
-        header: FstpHeader { 
-            flag: Flag::Add,
-            data_size: data_size as u16 
-        },
-        data: Some(&raw_data.as_slice()[..data_size]) 
+fn human(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn rate(bytes_per_sec: u64) -> String {
+    if bytes_per_sec == 0 {
+        String::from("unlimited")
+    } else {
+        format!("{}/s", human(bytes_per_sec))
+    }
+}
+
+fn help(out: &mut dyn Write) -> io::Result<()> {
+    let rows: Vec<Vec<String>> = HELP
+        .iter()
+        .map(|(cmd, what)| vec![cmd.to_string(), what.to_string()])
+        .collect();
+    print_table(out, &["COMMAND", "DESCRIPTION"], &rows)
+}
+
+fn status(
+    out: &mut dyn Write,
+    tracker: &Mutex<TrackerClient>,
+    node: &Node,
+) -> anyhow::Result<()> {
+    let tracker_addr = tracker.lock().unwrap().peer_addr()?;
+    let metas = node.store.metas();
+    let complete = metas.iter().filter(|m| m.has_full_file).count();
+    let (active, queued) = node.stats.uploads();
+    let (up_global, up_peer) = node.upload_limit.rates();
+    let (down_global, down_peer) = node.download_limit.rates();
+    let transport = if node.config.udp {
+        format!("udp ({:?})", node.config.congestion).to_lowercase()
+    } else {
+        String::from("tcp")
     };
-    println!("{:#?}",msg);
-  
-    let mut msg_buffer = [0u8;2000];
-    let msg_size = msg.as_bytes(&mut msg_buffer)?;
+    let rows = [
+        ("tracker", tracker_addr.to_string()),
+        ("port", node.port.to_string()),
+        ("shared dir", node.store.dir().display().to_string()),
+        (
+            "files",
+            format!(
+                "{} complete, {} partial",
+                complete,
+                metas.len() - complete
+            ),
+        ),
+        ("downloads", node.downloader.downloads().len().to_string()),
+        ("uploads", format!("{} active, {} queued", active, queued)),
+        ("transport", transport),
+        (
+            "upload limit",
+            format!("{} global, {} per peer", rate(up_global), rate(up_peer)),
+        ),
+        (
+            "download limit",
+            format!(
+                "{} global, {} per peer",
+                rate(down_global),
+                rate(down_peer)
+            ),
+        ),
+    ];
+    print_fields(out, &rows)?;
+    Ok(())
+}
 
-    stream.write_all(&msg_buffer[..msg_size])?;
-    stream.flush()?;
+fn list(
+    out: &mut dyn Write,
+    tracker: &Mutex<TrackerClient>,
+    node: &Node,
+) -> anyhow::Result<()> {
+    let mut files = tracker.lock().unwrap().list()?;
+    files.sort();
+    let rows: Vec<Vec<String>> = files
+        .into_iter()
+        .map(|name| {
+            let local = match node.store.meta(&name) {
+                Some(m) if m.has_full_file => "complete",
+                Some(_) => "partial",
+                None => "-",
+            };
+            vec![name, local.to_string()]
+        })
+        .collect();
+    print_table(out, &["FILE", "LOCAL"], &rows)?;
     Ok(())
 }
 
-fn get_files_meta() -> Vec<FileMeta> {
-    let mut files_meta = Vec::new();
-    let mut config = File::open("./node.config").expect("No config file found");
-
-    let mut shared_path = String::new();
-    config
-        .read_to_string(&mut shared_path)
-        .expect("Inválid path");
-
-    let shared_dir: ReadDir =
-        read_dir(shared_path.trim_end()).expect(
-            &format!("failed to read directory: {}",shared_path)
-        );
-
-    for try_entry in shared_dir {
-        let entry = try_entry.expect("failed to read entry");
-        let path = entry.path();
-
-        if path.is_file() 
-        && let Ok(meta) = entry.metadata() 
-        && let Some(name) = path.file_name().and_then(|os_str| os_str.to_str())
-        {
-            let f_size = meta.len();
-            let f_m = FileMeta {
-                f_size,
-                has_full_file: true,
-                blocks_len: 0,
-                name_len: name.len() as u16,
-                blocks: BitVec::<u8,Msb0>::new(),
-                name:name.to_string()
+fn info(out: &mut dyn Write, node: &Node, f_name: &str) -> anyhow::Result<()> {
+    let Some(meta) = node.store.meta(f_name) else {
+        bail!("Not shared: {}", f_name);
+    };
+    let state = if meta.has_full_file {
+        "complete"
+    } else if node.downloader.downloads().iter().any(|(n, _)| n == f_name) {
+        "downloading"
+    } else {
+        "partial"
+    };
+    let path = node.store.path(f_name).unwrap_or_default();
+    let rows = [
+        ("name", meta.name.clone()),
+        ("path", path.display().to_string()),
+        ("size", human(meta.f_size)),
+        ("block size", human(meta.block_size as u64)),
+        (
+            "blocks",
+            format!("{}/{}", meta.blocks.count_ones(), meta.blocks_len),
+        ),
+        ("state", state.to_string()),
+    ];
+    print_fields(out, &rows)?;
+    Ok(())
+}
+
+fn peers(
+    out: &mut dyn Write,
+    tracker: &Mutex<TrackerClient>,
+    node: &Node,
+    f_name: &str,
+) -> anyhow::Result<()> {
+    let p_w_f = tracker.lock().unwrap().locate(f_name)?;
+    let mut held: HashMap<SocketAddr, usize> = HashMap::new();
+    for addr in p_w_f.peers_with_blocks.values().flatten() {
+        *held.entry(*addr).or_default() += 1;
+    }
+    let mut addrs: Vec<SocketAddr> =
+        p_w_f.peers_with_file.iter().copied().collect();
+    addrs.extend(
+        held.keys()
+            .filter(|addr| !p_w_f.peers_with_file.contains(addr)),
+    );
+    addrs.sort();
+    let table = node.downloader.peer_table();
+    let rows: Vec<Vec<String>> = addrs
+        .into_iter()
+        .map(|addr| {
+            let has = if p_w_f.peers_with_file.contains(&addr) {
+                String::from("file")
+            } else {
+                format!("{}/{} blocks", held[&addr], p_w_f.n_blocks)
+            };
+            let stats = table.get(addr);
+            let rtt = stats
+                .rtt
+                .map_or(String::from("-"), |d| format!("{} ms", d.as_millis()));
+            let speed = match stats.throughput {
+                Some(t) => format!("{}/s", human(t as u64)),
+                None => String::from("-"),
             };
-            files_meta.push(f_m);
+            vec![addr.to_string(), has, rtt, speed]
+        })
+        .collect();
+    print_table(out, &["PEER", "HAS", "RTT", "THROUGHPUT"], &rows)?;
+    Ok(())
+}
+
+// Corre o download numa thread; o fim é anunciado ao tracker
+fn get_in_background<'s>(
+    out: &mut dyn Write,
+    s: &'s thread::Scope<'s, '_>,
+    tracker: &'s Mutex<TrackerClient>,
+    node: &'s Node,
+    f_name: &str,
+) -> anyhow::Result<()> {
+    // Aceita `docs\a.pdf` ou `./docs/a.pdf` para `docs/a.pdf`
+    let Some(f_name) = normalize_name(f_name) else {
+        bail!("Invalid file name: {}", f_name);
+    };
+    let f_name = f_name.as_str();
+    if node.store.meta(f_name).is_some_and(|m| m.has_full_file) {
+        bail!("Already have {}", f_name);
+    }
+    if node.downloader.downloads().iter().any(|(n, _)| n == f_name) {
+        bail!("{} is already being downloaded", f_name);
+    }
+    let p_w_f = tracker.lock().unwrap().locate(f_name)?;
+    let f_name = f_name.to_string();
+    writeln!(out, "Downloading {} (see downloads)", f_name)?;
+    s.spawn(move || {
+        match node.downloader.download(&node.store, &f_name, &p_w_f) {
+            Ok(meta) => {
+                println!("Downloaded {}", f_name);
+                let res = tracker.lock().unwrap().announce(&[meta]);
+                if let Err(e) = res {
+                    println!("Couldn't announce {}: {}", f_name, e);
+                }
+            }
+            Err(e) => println!("Download failed: {}", e),
+        }
+    });
+    Ok(())
+}
+
+fn downloads(out: &mut dyn Write, node: &Node) -> io::Result<()> {
+    let mut active = node.downloader.downloads();
+    active.sort_by(|a, b| a.0.cmp(&b.0));
+    let rows: Vec<Vec<String>> = active
+        .into_iter()
+        .map(|(name, progress)| {
+            let (done, total) = progress.blocks();
+            let percent = if total > 0 {
+                format!("{}%", done as u64 * 100 / total as u64)
+            } else {
+                String::from("-")
+            };
+            let secs = progress.started.elapsed().as_secs_f64();
+            let speed = (progress.bytes() as f64 / secs.max(0.001)) as u64;
+            vec![
+                name,
+                format!("{}/{}", done, total),
+                percent,
+                format!("{}/s", human(speed)),
+                format!("{:.0}s", secs),
+            ]
+        })
+        .collect();
+    print_table(out, &["FILE", "BLOCKS", "DONE", "SPEED", "ELAPSED"], &rows)
+}
+
+fn cancel(
+    out: &mut dyn Write,
+    node: &Node,
+    f_name: &str,
+) -> anyhow::Result<()> {
+    if !node.downloader.cancel(f_name) {
+        bail!("Not downloading {}", f_name);
+    }
+    writeln!(out, "Cancelling {}", f_name)?;
+    Ok(())
+}
+
+fn share(
+    out: &mut dyn Write,
+    tracker: &Mutex<TrackerClient>,
+    node: &Node,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let meta = node.store.share(path)?;
+    let name = meta.name.clone();
+    tracker.lock().unwrap().announce(&[meta])?;
+    writeln!(out, "Sharing {}", name)?;
+    Ok(())
+}
+
+fn unshare(
+    out: &mut dyn Write,
+    tracker: &Mutex<TrackerClient>,
+    node: &Node,
+    f_name: &str,
+) -> anyhow::Result<()> {
+    if node.downloader.downloads().iter().any(|(n, _)| n == f_name) {
+        bail!("{} is being downloaded (cancel it first)", f_name);
+    }
+    if node.store.unshare(f_name).is_none() {
+        bail!("Not shared: {}", f_name);
+    }
+    tracker.lock().unwrap().withdraw(&[f_name])?;
+    writeln!(out, "No longer sharing {}", f_name)?;
+    Ok(())
+}
+
+fn roots(out: &mut dyn Write, node: &Node) -> io::Result<()> {
+    let rows: Vec<Vec<String>> = node
+        .store
+        .roots()
+        .iter()
+        .enumerate()
+        .map(|(i, root)| {
+            let mode = if root.read_only { "ro" } else { "rw" };
+            vec![
+                root.prefix.clone().unwrap_or_else(|| String::from("-")),
+                root.path.display().to_string(),
+                mode.to_string(),
+                node.store.count(i).to_string(),
+            ]
+        })
+        .collect();
+    print_table(out, &["ROOT", "PATH", "MODE", "FILES"], &rows)
+}
+
+// Anuncia os ficheiros novos da raiz e retira os que desapareceram
+fn rescan(
+    out: &mut dyn Write,
+    tracker: &Mutex<TrackerClient>,
+    node: &Node,
+    root: &str,
+) -> anyhow::Result<()> {
+    let Some(i) = node.store.find_root(root) else {
+        bail!("Unknown root: {} (see roots)", root);
+    };
+    let (added, removed) = node.store.rescan(i)?;
+    let mut tracker = tracker.lock().unwrap();
+    if !added.is_empty() {
+        tracker.announce(&added)?;
+    }
+    if !removed.is_empty() {
+        let names: Vec<&str> = removed.iter().map(String::as_str).collect();
+        tracker.withdraw(&names)?;
+    }
+    writeln!(
+        out,
+        "{} new or changed, {} removed",
+        added.len(),
+        removed.len()
+    )?;
+    Ok(())
+}
+
+// limit [upload|download] [global|peer] <KiB/s>
+fn limit(
+    out: &mut dyn Write,
+    node: &Node,
+    args: &[&str],
+) -> anyhow::Result<()> {
+    if let [dir, scope, val] = args[..] {
+        let limiter = match dir {
+            "upload" => &node.upload_limit,
+            "download" => &node.download_limit,
+            _ => bail!("Usage: limit [upload|download] [global|peer] <KiB/s>"),
+        };
+        let rate = parse_kib(scope, val)?;
+        match scope {
+            "global" => limiter.set_global(rate),
+            "peer" => limiter.set_per_peer(rate),
+            _ => bail!("Usage: limit [upload|download] [global|peer] <KiB/s>"),
         }
+    } else if !args.is_empty() {
+        bail!("Usage: limit [upload|download] [global|peer] <KiB/s>");
     }
-    files_meta
+    let limiters = [
+        ("upload", &node.upload_limit),
+        ("download", &node.download_limit),
+    ];
+    let rows: Vec<Vec<String>> = limiters
+        .into_iter()
+        .map(|(dir, limiter)| {
+            let (global, peer) = limiter.rates();
+            vec![dir.to_string(), rate(global), rate(peer)]
+        })
+        .collect();
+    print_table(out, &["DIRECTION", "GLOBAL", "PER PEER"], &rows)?;
+    Ok(())
+}
+
+// Janela e perdas dos envios por UDP a cada peer
+fn congestion(out: &mut dyn Write, node: &Node) -> io::Result<()> {
+    writeln!(out, "algorithm: {:?}", node.udp.config().congestion)?;
+    let rows: Vec<Vec<String>> = node
+        .udp
+        .link_stats()
+        .into_iter()
+        .map(|(peer, stats)| {
+            let srtt = stats.srtt.map_or(0, |d| d.as_millis());
+            vec![
+                peer.to_string(),
+                stats.cwnd.to_string(),
+                format!("{} ms", srtt),
+                format!("{} ms", stats.rto.as_millis()),
+                format!("{:.1}%", stats.loss() * 100.0),
+                format!("{}/{}", stats.retransmitted, stats.sent),
+                stats.timeouts.to_string(),
+            ]
+        })
+        .collect();
+    print_table(
+        out,
+        &["PEER", "CWND", "SRTT", "RTO", "LOSS", "RESENT", "TIMEOUTS"],
+        &rows,
+    )
 }
 
+// Envia a carga periodicamente, até a ligação ao tracker fechar
+fn report_load(tracker: &Mutex<TrackerClient>, stats: &UploadStats) {
+    loop {
+        thread::sleep(STATS_INTERVAL);
+        let load = stats.report();
+        // Só a ligação perdida faz desistir
+        match tracker.lock().unwrap().report_load(&load) {
+            Ok(()) => {}
+            Err(e) if e.is_disconnect() => return,
+            Err(e) => println!("Couldn't report load: {}", e),
+        }
+    }
+}
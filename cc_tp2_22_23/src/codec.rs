@@ -0,0 +1,201 @@
+//! Codificação das mensagens do tracker (`fstp`, `file_meta`,
+//! `peers_with_blocks`, `load`): os traits comuns, os erros, e a leitura
+//! com verificação de limites que os descodificadores partilham.
+use std::fmt;
+use std::io;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    /// Os bytes acabam antes do campo indicado.
+    Truncated(&'static str),
+    /// Byte que não corresponde a nenhuma `Flag`.
+    InvalidFlag(u8),
+    /// Texto que não é UTF-8 válido.
+    InvalidUtf8(&'static str),
+    /// Valor que o campo não pode ter.
+    InvalidValue { field: &'static str, value: u64 },
+    /// Dados com `len` bytes, mais do que os `max` que cabem na mensagem.
+    TooLarge { len: usize, max: usize },
+    /// O buffer dado para codificar tem menos bytes do que os precisos.
+    BufferTooSmall { needed: usize, available: usize },
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Truncated(field) => {
+                write!(f, "Message truncated before {}", field)
+            }
+            CodecError::InvalidFlag(byte) => {
+                write!(f, "Invalid flag: {}", byte)
+            }
+            CodecError::InvalidUtf8(field) => {
+                write!(f, "{} is not valid UTF-8", field)
+            }
+            CodecError::InvalidValue { field, value } => {
+                write!(f, "Invalid value for {}: {}", field, value)
+            }
+            CodecError::TooLarge { len, max } => {
+                write!(f, "{} bytes don't fit in a message (max {})", len, max)
+            }
+            CodecError::BufferTooSmall { needed, available } => write!(
+                f,
+                "Buffer too small: {} bytes needed, {} available",
+                needed, available
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<CodecError> for io::Error {
+    fn from(e: CodecError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Tipos que se escrevem no formato da rede.
+pub trait Encode {
+    /// Bytes exatos que `as_bytes` escreve.
+    fn encoded_len(&self) -> usize;
+
+    /// Escreve no início de `buf`, que tem de ter pelo menos
+    /// `encoded_len` bytes, e devolve quantos escreveu.
+    fn as_bytes(&self, buf: &mut [u8]) -> Result<usize, CodecError>;
+
+    /// Acrescenta a codificação ao fim de `buf`, que cresce o que for
+    /// preciso. Em caso de erro, `buf` fica como estava.
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<usize, CodecError> {
+        let start = buf.len();
+        buf.resize(start + self.encoded_len(), 0);
+        match self.as_bytes(&mut buf[start..]) {
+            Ok(size) => {
+                buf.truncate(start + size);
+                Ok(size)
+            }
+            Err(e) => {
+                buf.truncate(start);
+                Err(e)
+            }
+        }
+    }
+
+    fn to_vec(&self) -> Result<Vec<u8>, CodecError> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        self.encode(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Bytes já codificados, p.e. texto.
+impl Encode for [u8] {
+    fn encoded_len(&self) -> usize {
+        self.len()
+    }
+
+    fn as_bytes(&self, buf: &mut [u8]) -> Result<usize, CodecError> {
+        check_space(buf, self.len())?;
+        buf[..self.len()].copy_from_slice(self);
+        Ok(self.len())
+    }
+}
+
+/// Tipos que se leem do formato da rede, possivelmente com referências
+/// para os bytes lidos.
+pub trait Decode<'a>: Sized {
+    /// Lê do início de `bytes`; devolve também os bytes usados, para ler
+    /// vários valores seguidos.
+    fn decode(bytes: &'a [u8]) -> Result<(usize, Self), CodecError>;
+}
+
+/// Leitura sequencial de uma mensagem: cada campo dá erro em vez de
+/// pânico se os bytes não chegarem.
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, pos: 0 }
+    }
+
+    /// Bytes já lidos.
+    pub(crate) fn pos(&self) -> usize {
+        self.pos
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    pub(crate) fn bytes(
+        &mut self,
+        n: usize,
+        field: &'static str,
+    ) -> Result<&'a [u8], CodecError> {
+        let rest = &self.bytes[self.pos..];
+        let Some(bytes) = rest.get(..n) else {
+            return Err(CodecError::Truncated(field));
+        };
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(
+        &mut self,
+        field: &'static str,
+    ) -> Result<[u8; N], CodecError> {
+        let rest = &self.bytes[self.pos..];
+        let Some((array, _)) = rest.split_first_chunk::<N>() else {
+            return Err(CodecError::Truncated(field));
+        };
+        self.pos += N;
+        Ok(*array)
+    }
+
+    pub(crate) fn u8(&mut self, field: &'static str) -> Result<u8, CodecError> {
+        Ok(self.array::<1>(field)?[0])
+    }
+
+    pub(crate) fn u16(
+        &mut self,
+        field: &'static str,
+    ) -> Result<u16, CodecError> {
+        Ok(u16::from_be_bytes(self.array(field)?))
+    }
+
+    pub(crate) fn u32(
+        &mut self,
+        field: &'static str,
+    ) -> Result<u32, CodecError> {
+        Ok(u32::from_be_bytes(self.array(field)?))
+    }
+
+    pub(crate) fn u64(
+        &mut self,
+        field: &'static str,
+    ) -> Result<u64, CodecError> {
+        Ok(u64::from_be_bytes(self.array(field)?))
+    }
+}
+
+/// Os bytes como texto, para os campos com nomes.
+pub(crate) fn utf8<'a>(
+    bytes: &'a [u8],
+    field: &'static str,
+) -> Result<&'a str, CodecError> {
+    std::str::from_utf8(bytes).map_err(|_| CodecError::InvalidUtf8(field))
+}
+
+/// Falha se `buf` não tiver espaço para `needed` bytes.
+pub(crate) fn check_space(buf: &[u8], needed: usize) -> Result<(), CodecError> {
+    if buf.len() < needed {
+        return Err(CodecError::BufferTooSmall {
+            needed,
+            available: buf.len(),
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,313 @@
+//! Configuração do nó (`node.config`) e do tracker (`tracker.config`).
+//!
+//! Uma opção `chave = valor` por linha; linhas vazias e começadas por
+//! `#` são ignoradas. No nó, uma linha sem `=` é o diretório partilhado,
+//! o que mantém válidos os ficheiros antigos que só tinham o caminho.
+//!
+//! Além do diretório partilhado, o nó pode partilhar outras raízes, uma
+//! por linha: `root = <caminho>[, prefix = <nome>][, ro|rw]`.
+use crate::congestion::Algorithm;
+use crate::ranking::RankingPolicy;
+use crate::reliable;
+use crate::store::is_valid_name;
+use crate::transfer::{Transport, TRANSFER_PORT};
+use anyhow::{bail, Context};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Diretório partilhado pelo nó. Os ficheiros são anunciados como
+/// `<prefixo>/<caminho relativo>`, para não colidirem com os de outras
+/// raízes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SharedRoot {
+    pub path: PathBuf,
+    /// `None` só no diretório partilhado principal (`shared`).
+    pub prefix: Option<String>,
+    /// Os downloads nunca são escritos aqui.
+    pub read_only: bool,
+}
+
+impl SharedRoot {
+    /// Raiz sem prefixo onde vão parar os downloads.
+    pub fn main(path: PathBuf) -> Self {
+        SharedRoot {
+            path,
+            prefix: None,
+            read_only: false,
+        }
+    }
+
+    /// `<caminho>[, prefix = <nome>][, ro|rw]`; sem prefixo fica o nome
+    /// do diretório.
+    fn parse(val: &str) -> anyhow::Result<Self> {
+        let mut opts = val.split(',').map(str::trim);
+        let path = PathBuf::from(opts.next().unwrap_or_default());
+        let mut prefix = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(String::from);
+        let mut read_only = false;
+        for opt in opts {
+            match opt.split_once('=').map(|(k, v)| (k.trim(), v.trim())) {
+                Some(("prefix", val)) => prefix = Some(val.to_string()),
+                None if opt == "ro" => read_only = true,
+                None if opt == "rw" => read_only = false,
+                _ => {
+                    bail!("Invalid option for root {}: {}", path.display(), opt)
+                }
+            }
+        }
+        // Um só componente, que não saia do diretório
+        match prefix {
+            Some(p) if is_valid_name(&p) && !p.contains('/') => {
+                Ok(SharedRoot {
+                    path,
+                    prefix: Some(p),
+                    read_only,
+                })
+            }
+            _ => bail!("Invalid prefix for root: {}", val),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    pub shared: PathBuf,
+    /// Outras raízes partilhadas, além de `shared`.
+    pub roots: Vec<SharedRoot>,
+    /// Porta (TCP e UDP) onde o nó serve blocos; 0 escolhe uma livre.
+    pub port: u16,
+    /// Blocos pedidos em simultâneo por cada download.
+    pub max_parallel_blocks: usize,
+    /// Blocos pedidos em simultâneo por todos os downloads do nó.
+    pub max_total_blocks: usize,
+    /// Tempo ao fim do qual um pedido de bloco é entregue a outro peer.
+    pub slow_block_timeout: Duration,
+    /// Blocos em falta a partir dos quais cada um, depois de pedido, é
+    /// pedido também aos outros peers que o têm (endgame); 0 desliga.
+    pub endgame_blocks: usize,
+    /// Limites de largura de banda em bytes/s (0 = sem limite). Nas
+    /// opções do ficheiro são dados em KiB/s.
+    pub upload_limit: u64,
+    pub download_limit: u64,
+    pub peer_upload_limit: u64,
+    pub peer_download_limit: u64,
+    /// Pedir os blocos aos outros nós por UDP em vez de TCP.
+    pub udp: bool,
+    /// Controlo de congestão dos envios por UDP.
+    pub congestion: Algorithm,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            shared: PathBuf::from("."),
+            roots: Vec::new(),
+            port: TRANSFER_PORT,
+            max_parallel_blocks: 4,
+            max_total_blocks: 16,
+            slow_block_timeout: Duration::from_secs(3),
+            endgame_blocks: 4,
+            upload_limit: 0,
+            download_limit: 0,
+            peer_upload_limit: 0,
+            peer_download_limit: 0,
+            udp: false,
+            congestion: Algorithm::default(),
+        }
+    }
+}
+
+impl NodeConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let raw = fs::read_to_string(path).with_context(|| {
+            format!("No config file found: {}", path.display())
+        })?;
+        Self::parse(&raw)
+    }
+
+    pub fn parse(raw: &str) -> anyhow::Result<Self> {
+        let mut config = NodeConfig::default();
+        for line in raw.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, val)) = line.split_once('=') else {
+                config.shared = PathBuf::from(line);
+                continue;
+            };
+            let val = val.trim();
+            match key.trim() {
+                "shared" => config.shared = PathBuf::from(val),
+                "root" => {
+                    let root = SharedRoot::parse(val)?;
+                    if config.roots.iter().any(|r| r.prefix == root.prefix) {
+                        bail!("Two roots with the same prefix: {}", val);
+                    }
+                    config.roots.push(root);
+                }
+                "port" => {
+                    config.port = match val.parse() {
+                        Ok(port) => port,
+                        _ => bail!("Invalid value for port: {}", val),
+                    }
+                }
+                "max_parallel_blocks" => {
+                    config.max_parallel_blocks = parse_positive(key, val)?
+                }
+                "max_total_blocks" => {
+                    config.max_total_blocks = parse_positive(key, val)?
+                }
+                "slow_block_timeout_ms" => {
+                    let ms = parse_positive(key, val)?;
+                    config.slow_block_timeout = Duration::from_millis(ms)
+                }
+                // 0 desliga o endgame
+                "endgame_blocks" => {
+                    config.endgame_blocks = match val.parse() {
+                        Ok(n) => n,
+                        _ => bail!("Invalid value for {}: {}", key.trim(), val),
+                    }
+                }
+                "upload_limit_kib" => {
+                    config.upload_limit = parse_kib(key, val)?
+                }
+                "download_limit_kib" => {
+                    config.download_limit = parse_kib(key, val)?
+                }
+                "peer_upload_limit_kib" => {
+                    config.peer_upload_limit = parse_kib(key, val)?
+                }
+                "peer_download_limit_kib" => {
+                    config.peer_download_limit = parse_kib(key, val)?
+                }
+                "transport" => {
+                    config.udp = match val {
+                        "tcp" => false,
+                        "udp" => true,
+                        _ => bail!("Invalid value for transport: {}", val),
+                    }
+                }
+                "congestion" => config.congestion = val.parse()?,
+                other => bail!("Unknown config option: {}", other),
+            }
+        }
+        Ok(config)
+    }
+
+    /// Todas as raízes partilhadas: `shared` primeiro, sem prefixo.
+    pub fn shared_roots(&self) -> Vec<SharedRoot> {
+        let main = SharedRoot::main(self.shared.clone());
+        std::iter::once(main).chain(self.roots.clone()).collect()
+    }
+
+    /// Configuração dos sockets UDP do nó.
+    pub fn reliable(&self) -> reliable::Config {
+        reliable::Config {
+            congestion: self.congestion,
+            ..reliable::Config::default()
+        }
+    }
+
+    pub fn transport(&self) -> Transport {
+        if self.udp {
+            Transport::udp(self.reliable())
+        } else {
+            Transport::Tcp
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackerConfig {
+    /// Peers devolvidos, no máximo, por ficheiro.
+    pub max_peers_per_file: usize,
+    /// Peers devolvidos, no máximo, por bloco.
+    pub max_peers_per_block: usize,
+    pub ranking: RankingPolicy,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        TrackerConfig {
+            max_peers_per_file: 16,
+            max_peers_per_block: 4,
+            ranking: RankingPolicy::default(),
+        }
+    }
+}
+
+impl TrackerConfig {
+    /// Sem ficheiro ficam os valores por omissão.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(raw) => Self::parse(&raw),
+            Err(_) => Ok(TrackerConfig::default()),
+        }
+    }
+
+    /// `ranking` escolhe uma política predefinida; as opções `weight_*`
+    /// mudam os pesos um a um.
+    pub fn parse(raw: &str) -> anyhow::Result<Self> {
+        let mut config = TrackerConfig::default();
+        for line in raw.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, val)) = line.split_once('=') else {
+                bail!("Invalid config line: {}", line);
+            };
+            let val = val.trim();
+            let ranking = &mut config.ranking;
+            match key.trim() {
+                "max_peers_per_file" => {
+                    config.max_peers_per_file = parse_positive(key, val)?
+                }
+                "max_peers_per_block" => {
+                    config.max_peers_per_block = parse_positive(key, val)?
+                }
+                "ranking" => *ranking = val.parse()?,
+                "weight_seeder" => ranking.seeder = parse_weight(key, val)?,
+                "weight_load" => ranking.load = parse_weight(key, val)?,
+                "weight_proximity" => {
+                    ranking.proximity = parse_weight(key, val)?
+                }
+                "weight_random" => ranking.random = parse_weight(key, val)?,
+                other => bail!("Unknown config option: {}", other),
+            }
+        }
+        Ok(config)
+    }
+}
+
+fn parse_weight(key: &str, val: &str) -> anyhow::Result<f64> {
+    match val.parse::<f64>() {
+        Ok(w) if w >= 0.0 && w.is_finite() => Ok(w),
+        _ => bail!("Invalid value for {}: {}", key.trim(), val),
+    }
+}
+
+fn parse_positive<T>(key: &str, val: &str) -> anyhow::Result<T>
+where
+    T: FromStr + PartialOrd + Default,
+{
+    match val.parse::<T>() {
+        Ok(n) if n > T::default() => Ok(n),
+        _ => bail!("Invalid value for {}: {}", key.trim(), val),
+    }
+}
+
+/// KiB/s para bytes/s.
+pub fn parse_kib(key: &str, val: &str) -> anyhow::Result<u64> {
+    match val
+        .parse::<u64>()
+        .ok()
+        .and_then(|kib| kib.checked_mul(1024))
+    {
+        Some(bytes) => Ok(bytes),
+        None => bail!("Invalid value for {}: {}", key.trim(), val),
+    }
+}
@@ -0,0 +1,205 @@
+//! Controlo de congestão das transferências sobre UDP.
+//!
+//! A janela de congestão limita os fragmentos por confirmar enviados a
+//! cada peer. Os algoritmos implementam `CongestionControl` e são
+//! escolhidos na configuração com um `Algorithm`.
+use anyhow::bail;
+use std::fmt::Debug;
+use std::str::FromStr;
+
+/// Janela com que começa cada ligação, em fragmentos.
+const INITIAL_WINDOW: f64 = 4.0;
+/// Menor limiar de slow start depois de uma perda.
+const MIN_SSTHRESH: f64 = 2.0;
+
+pub trait CongestionControl: Debug + Send {
+    fn name(&self) -> &'static str;
+
+    /// Fragmentos que podem estar por confirmar.
+    fn window(&self) -> u32;
+
+    /// `acked` fragmentos novos foram confirmados.
+    fn on_ack(&mut self, acked: u32);
+
+    /// Perda detetada pelo bitmap (NACK) de um ACK: falta um fragmento
+    /// enviado antes de outro que já chegou. Conta uma vez por janela.
+    fn on_loss(&mut self);
+
+    /// O timer de retransmissão expirou.
+    fn on_timeout(&mut self);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Algorithm {
+    #[default]
+    Aimd,
+    Fixed,
+}
+
+impl Algorithm {
+    /// A janela nunca passa de `max_window`.
+    pub fn build(self, max_window: u32) -> Box<dyn CongestionControl> {
+        match self {
+            Algorithm::Aimd => Box::new(Aimd::new(max_window)),
+            Algorithm::Fixed => Box::new(Fixed::new(max_window)),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "aimd" => Ok(Algorithm::Aimd),
+            "fixed" => Ok(Algorithm::Fixed),
+            _ => bail!("Unknown congestion control algorithm: {}", s),
+        }
+    }
+}
+
+/// Aumento aditivo e diminuição multiplicativa, com slow start (como o
+/// TCP Reno): a janela cresce um fragmento por ACK até ao limiar e um
+/// fragmento por janela depois dele. Uma perda corta-a para metade e um
+/// timeout volta a um fragmento.
+#[derive(Debug, Clone)]
+pub struct Aimd {
+    cwnd: f64,
+    ssthresh: f64,
+    max: f64,
+}
+
+impl Aimd {
+    pub fn new(max_window: u32) -> Self {
+        let max = max_window.max(1) as f64;
+        Aimd {
+            cwnd: INITIAL_WINDOW.min(max),
+            ssthresh: max,
+            max,
+        }
+    }
+
+    fn halve(&mut self) {
+        self.ssthresh = (self.cwnd / 2.0).max(MIN_SSTHRESH);
+    }
+}
+
+impl CongestionControl for Aimd {
+    fn name(&self) -> &'static str {
+        "aimd"
+    }
+
+    fn window(&self) -> u32 {
+        self.cwnd as u32
+    }
+
+    fn on_ack(&mut self, acked: u32) {
+        for _ in 0..acked {
+            if self.cwnd < self.ssthresh {
+                self.cwnd += 1.0;
+            } else {
+                self.cwnd += 1.0 / self.cwnd;
+            }
+        }
+        self.cwnd = self.cwnd.min(self.max);
+    }
+
+    fn on_loss(&mut self) {
+        self.halve();
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_timeout(&mut self) {
+        self.halve();
+        self.cwnd = 1.0;
+    }
+}
+
+/// Janela constante, sem reagir às perdas.
+#[derive(Debug, Clone)]
+pub struct Fixed {
+    window: u32,
+}
+
+impl Fixed {
+    pub fn new(window: u32) -> Self {
+        Fixed {
+            window: window.max(1),
+        }
+    }
+}
+
+impl CongestionControl for Fixed {
+    fn name(&self) -> &'static str {
+        "fixed"
+    }
+
+    fn window(&self) -> u32 {
+        self.window
+    }
+
+    fn on_ack(&mut self, _acked: u32) {}
+
+    fn on_loss(&mut self) {}
+
+    fn on_timeout(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aimd_slow_start_then_congestion_avoidance() {
+        let mut cc = Aimd::new(64);
+        assert_eq!(cc.window(), 4);
+        // Slow start: um fragmento por ACK
+        cc.on_ack(4);
+        assert_eq!(cc.window(), 8);
+
+        // A perda corta para metade e passa a crescer devagar
+        cc.on_loss();
+        assert_eq!(cc.window(), 4);
+        assert_eq!(cc.ssthresh, 4.0);
+        cc.on_ack(4);
+        assert_eq!(cc.window(), 4);
+        cc.on_ack(1);
+        assert_eq!(cc.window(), 5);
+    }
+
+    #[test]
+    fn aimd_timeout_restarts_from_one() {
+        let mut cc = Aimd::new(64);
+        cc.on_ack(12);
+        assert_eq!(cc.window(), 16);
+        cc.on_timeout();
+        assert_eq!(cc.window(), 1);
+        assert_eq!(cc.ssthresh, 8.0);
+        // Volta em slow start até ao novo limiar
+        cc.on_ack(7);
+        assert_eq!(cc.window(), 8);
+
+        // O limiar nunca desce abaixo do mínimo
+        cc.on_timeout();
+        cc.on_timeout();
+        cc.on_timeout();
+        assert_eq!(cc.ssthresh, MIN_SSTHRESH);
+        assert_eq!(cc.window(), 1);
+    }
+
+    #[test]
+    fn windows_stay_within_the_maximum() {
+        let mut cc = Aimd::new(10);
+        cc.on_ack(1000);
+        assert_eq!(cc.window(), 10);
+        assert_eq!(Aimd::new(2).window(), 2);
+        assert_eq!(Aimd::new(0).window(), 1);
+
+        let mut fixed = Algorithm::Fixed.build(6);
+        fixed.on_loss();
+        fixed.on_timeout();
+        assert_eq!(fixed.window(), 6);
+        assert_eq!("aimd".parse::<Algorithm>().unwrap(), Algorithm::Aimd);
+        assert!("cubic".parse::<Algorithm>().is_err());
+    }
+}
@@ -0,0 +1,607 @@
+use crate::config::NodeConfig;
+use crate::file_meta::{FileMeta, BLOCK_SIZE};
+use crate::peer_stats::PeerTable;
+use crate::peers_with_blocks::PeersWithFile;
+use crate::ratelimit::RateLimiter;
+use crate::store::{
+    digest, is_valid_name, Digest, FileInfo, FileStore, PartialState,
+};
+use crate::transfer::{Canceller, PeerConn, Transport};
+use anyhow::{anyhow, bail};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::net::SocketAddr;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use threadpool::ThreadPool;
+
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Falhas a partir das quais um peer deixa de ser usado num download.
+const MAX_PEER_FAILURES: u32 = 3;
+const TICK: Duration = Duration::from_millis(100);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Idade a partir da qual o RTT de um peer volta a ser medido.
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+/// Espera máxima, num download que falhou, pelos pedidos cancelados:
+/// uma ligação a meio só é interrompida quando fica feita.
+const DRAIN_TIMEOUT: Duration = PEER_TIMEOUT;
+/// Intervalo mínimo entre gravações do estado durante um download. Ao
+/// retomar, os blocos são todos reverificados, por isso um estado
+/// atrasado só custa essa verificação.
+const SAVE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Resultado de um pedido de bloco: (bloco, peer, dados verificados).
+type Fetched = (u32, SocketAddr, anyhow::Result<Vec<u8>>);
+
+/// Pedido de bloco em curso.
+struct Job {
+    block: u32,
+    peer: SocketAddr,
+    started: Instant,
+    // Já foi entregue a outro peer por estar a demorar
+    reassigned: bool,
+    cancel: Arc<Cancel>,
+}
+
+/// Cancelamento de um pedido de bloco, antes ou durante a ligação.
+#[derive(Default)]
+struct Cancel {
+    cancelled: AtomicBool,
+    conn: Mutex<Option<Canceller>>,
+}
+
+impl Cancel {
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        if let Some(conn) = self.conn.lock().unwrap().take() {
+            conn.cancel();
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// Progresso de um download em curso.
+#[derive(Debug)]
+pub struct Progress {
+    pub started: Instant,
+    total: AtomicU32,
+    done: AtomicU32,
+    bytes: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+impl Progress {
+    fn new() -> Self {
+        Progress {
+            started: Instant::now(),
+            total: AtomicU32::new(0),
+            done: AtomicU32::new(0),
+            bytes: AtomicU64::new(0),
+            cancelled: AtomicBool::new(false),
+        }
+    }
+
+    /// Blocos já verificados e total de blocos (0 até se saber o
+    /// tamanho do ficheiro).
+    pub fn blocks(&self) -> (u32, u32) {
+        (
+            self.done.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Bytes recebidos desde o início deste download.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+// Tira o download da lista dos que estão em curso quando termina
+struct Active<'a> {
+    downloads: &'a Mutex<HashMap<String, Arc<Progress>>>,
+    name: &'a str,
+}
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.downloads.lock().unwrap().remove(self.name);
+    }
+}
+
+/// Descarrega ficheiros de vários peers em paralelo. Os pedidos de blocos
+/// de todos os downloads partilham a mesma pool, que limita o total.
+pub struct Downloader {
+    pool: ThreadPool,
+    limiter: Arc<RateLimiter>,
+    peers: Arc<PeerTable>,
+    transport: Transport,
+    max_parallel_blocks: usize,
+    slow_block_timeout: Duration,
+    endgame_blocks: usize,
+    active: Mutex<HashMap<String, Arc<Progress>>>,
+}
+
+impl Downloader {
+    pub fn new(config: &NodeConfig, limiter: Arc<RateLimiter>) -> Self {
+        Downloader {
+            pool: ThreadPool::new(config.max_total_blocks),
+            limiter,
+            peers: Arc::new(PeerTable::new()),
+            transport: config.transport(),
+            max_parallel_blocks: config.max_parallel_blocks,
+            slow_block_timeout: config.slow_block_timeout,
+            endgame_blocks: config.endgame_blocks,
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn peer_table(&self) -> &Arc<PeerTable> {
+        &self.peers
+    }
+
+    /// Downloads em curso, por nome.
+    pub fn downloads(&self) -> Vec<(String, Arc<Progress>)> {
+        let active = self.active.lock().unwrap();
+        active.iter().map(|(n, p)| (n.clone(), p.clone())).collect()
+    }
+
+    /// Interrompe o download de `name`, que pode ser retomado mais tarde.
+    /// Devolve `false` se não estiver em curso.
+    pub fn cancel(&self, name: &str) -> bool {
+        match self.active.lock().unwrap().get(name) {
+            Some(progress) => {
+                progress.cancelled.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Descarrega `name` dos peers indicados pelo tracker para a raiz do
+    /// `store` onde o nome cabe, criando os subdiretórios do nome
+    /// (`docs/a.pdf`). Se já existir um estado guardado para o ficheiro,
+    /// o download continua a partir dos blocos já verificados.
+    pub fn download(
+        &self,
+        store: &FileStore,
+        name: &str,
+        peers: &PeersWithFile,
+    ) -> anyhow::Result<FileMeta> {
+        // O nome vem do tracker: não pode escrever fora do diretório
+        if !is_valid_name(name) {
+            bail!("Invalid file name: {}", name);
+        }
+        let progress = Arc::new(Progress::new());
+        {
+            let mut active = self.active.lock().unwrap();
+            if active.contains_key(name) {
+                bail!("{} is already being downloaded", name);
+            }
+            active.insert(name.to_string(), progress.clone());
+        }
+        let _active = Active {
+            downloads: &self.active,
+            name,
+        };
+
+        let s_path = store.state_path(name);
+        let p_path = store.part_path(name);
+        if let Some(parent) = p_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut state = if s_path.exists() {
+            let mut state = PartialState::load(&s_path)?;
+            state.verify(&p_path)?;
+            state
+        } else {
+            let state = PartialState::new(
+                name.to_string(),
+                self.fetch_info(name, peers)?,
+            );
+            let part = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&p_path)?;
+            part.set_len(state.info.f_size)?;
+            state
+        };
+        state.save(&s_path)?;
+        store.add_partial(&state);
+        progress
+            .total
+            .store(state.info.n_blocks(), Ordering::Relaxed);
+        progress
+            .done
+            .store(state.blocks.count_ones() as u32, Ordering::Relaxed);
+        self.probe(&all_holders(peers));
+
+        let part = OpenOptions::new().write(true).open(&p_path)?;
+        let (tx, rx) = mpsc::channel();
+        let mut pending: VecDeque<u32> = state.missing().into();
+        let mut in_flight: Vec<Job> = Vec::new();
+        // Peers que já falharam (ou foram lentos) para cada bloco
+        let mut tried: HashMap<u32, HashSet<SocketAddr>> = HashMap::new();
+        let mut failures: HashMap<SocketAddr, u32> = HashMap::new();
+        let mut saved = Instant::now();
+
+        // Os erros saem do ciclo para o estado ser gravado antes
+        let res = 'fetch: loop {
+            if progress.is_cancelled() {
+                let err = anyhow!("Download of {} cancelled", name);
+                break 'fetch Err(err);
+            }
+
+            // Blocos a demorar demasiado passam para outro peer; o pedido
+            // original continua e conta se chegar primeiro
+            for job in in_flight.iter_mut() {
+                if !job.reassigned
+                    && job.started.elapsed() > self.slow_block_timeout
+                {
+                    job.reassigned = true;
+                    tried.entry(job.block).or_default().insert(job.peer);
+                    pending.push_front(job.block);
+                }
+            }
+
+            // Endgame: quando os últimos blocos já foram todos pedidos,
+            // são pedidos também aos outros peers que os têm (dentro do
+            // limite de pedidos); fica a primeira cópia verificada e os
+            // restantes pedidos são cancelados
+            let missing = state.missing();
+            let endgame = self.endgame_blocks > 0
+                && missing.len() <= self.endgame_blocks
+                && missing
+                    .iter()
+                    .all(|b| in_flight.iter().any(|j| j.block == *b));
+            if endgame {
+                'copies: for block in missing {
+                    for peer in usable(peers, block, &failures) {
+                        if in_flight.iter().filter(|j| !j.reassigned).count()
+                            >= self.max_parallel_blocks
+                        {
+                            break 'copies;
+                        }
+                        let requested = in_flight
+                            .iter()
+                            .any(|j| j.block == block && j.peer == peer);
+                        let failed = tried
+                            .get(&block)
+                            .is_some_and(|t| t.contains(&peer));
+                        if !requested && !failed {
+                            let expected = state.info.digests[block as usize];
+                            in_flight.push(
+                                self.request(name, block, peer, expected, &tx),
+                            );
+                        }
+                    }
+                }
+            }
+
+            let mut waiting = VecDeque::new();
+            while in_flight.iter().filter(|j| !j.reassigned).count()
+                < self.max_parallel_blocks
+            {
+                let Some(block) = pending.pop_front() else {
+                    break;
+                };
+                if state.blocks[block as usize] {
+                    continue;
+                }
+                let usable = usable(peers, block, &failures);
+                let busy = in_flight.iter().any(|j| j.block == block);
+                if !busy
+                    && usable.iter().all(|addr| {
+                        tried.get(&block).is_some_and(|t| t.contains(addr))
+                    })
+                {
+                    // Todos já foram tentados: nova ronda com os que ainda
+                    // não falharam demasiadas vezes
+                    tried.remove(&block);
+                }
+                let excluded = tried.get(&block);
+                let block_len = state.info.block_len(block);
+                let candidate = usable
+                    .into_iter()
+                    .filter(|addr| !excluded.is_some_and(|t| t.contains(addr)))
+                    .min_by_key(|addr| {
+                        // Cada pedido já em curso ao peer atrasa o próximo,
+                        // o que espalha a carga pelos mais rápidos
+                        let load = in_flight
+                            .iter()
+                            .filter(|j| j.peer == *addr)
+                            .count();
+                        self.peers.get(*addr).cost(block_len)
+                            * (load as u32 + 1)
+                    });
+                let Some(peer) = candidate else {
+                    if busy {
+                        // Resta esperar pelo pedido que já está em curso
+                        waiting.push_back(block);
+                        continue;
+                    }
+                    break 'fetch Err(no_peer(block, name));
+                };
+                let expected = state.info.digests[block as usize];
+                in_flight.push(self.request(name, block, peer, expected, &tx));
+            }
+            pending.extend(waiting);
+
+            if in_flight.is_empty() && pending.is_empty() {
+                break Ok(());
+            }
+
+            let (block, peer, res) = match rx.recv_timeout(TICK) {
+                Ok(done) => done,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => unreachable!(),
+            };
+            let job = in_flight
+                .iter()
+                .position(|j| j.block == block && j.peer == peer)
+                .map(|pos| in_flight.remove(pos));
+            if let (Some(job), Ok(data)) = (&job, &res) {
+                let took = job.started.elapsed();
+                self.peers.record_transfer(peer, data.len(), took);
+            }
+            if state.blocks[block as usize] {
+                continue;
+            }
+            match res {
+                Ok(data) => {
+                    let offset = state.info.block_offset(block);
+                    if let Err(e) = part.write_all_at(&data, offset) {
+                        break Err(e.into());
+                    }
+                    state.blocks.set(block as usize, true);
+                    if saved.elapsed() >= SAVE_INTERVAL {
+                        if let Err(e) = state.save(&s_path) {
+                            break Err(e);
+                        }
+                        saved = Instant::now();
+                    }
+                    store.set_block(name, block);
+                    progress.done.fetch_add(1, Ordering::Relaxed);
+                    progress
+                        .bytes
+                        .fetch_add(data.len() as u64, Ordering::Relaxed);
+                    // Outras cópias do bloco pedidas no endgame
+                    in_flight.retain(|j| {
+                        if j.block == block {
+                            j.cancel.cancel();
+                        }
+                        j.block != block
+                    });
+                }
+                Err(e) => {
+                    println!("{}: block {}: {}", peer, block, e);
+                    *failures.entry(peer).or_default() += 1;
+                    tried.entry(block).or_default().insert(peer);
+                    if !in_flight.iter().any(|j| j.block == block) {
+                        pending.push_back(block);
+                    }
+                }
+            }
+        };
+        if let Err(e) = res {
+            // Os pedidos em curso largam as ligações aos peers (e os
+            // tokens do limite) antes de o erro ser devolvido
+            for job in &in_flight {
+                job.cancel.cancel();
+            }
+            let deadline = Instant::now() + DRAIN_TIMEOUT;
+            while !in_flight.is_empty() {
+                let wait = deadline.saturating_duration_since(Instant::now());
+                let Ok((block, peer, _)) = rx.recv_timeout(wait) else {
+                    break;
+                };
+                in_flight.retain(|j| j.block != block || j.peer != peer);
+            }
+            // O que já foi verificado fica para quando for retomado
+            if let Err(save) = state.save(&s_path) {
+                println!("Couldn't save the state of {}: {}", name, save);
+            }
+            return Err(e);
+        }
+        part.sync_all()?;
+
+        store.complete(name)
+    }
+
+    /// Pede `block` a `peer` numa thread da pool; o resultado chega
+    /// por `tx`.
+    fn request(
+        &self,
+        name: &str,
+        block: u32,
+        peer: SocketAddr,
+        expected: Digest,
+        tx: &Sender<Fetched>,
+    ) -> Job {
+        let cancel = Arc::new(Cancel::default());
+        let job_cancel = cancel.clone();
+        let job_name = name.to_string();
+        let tx = tx.clone();
+        let limiter = self.limiter.clone();
+        let transport = self.transport.clone();
+        self.pool.execute(move || {
+            let res = fetch_block(
+                &job_name,
+                block,
+                peer,
+                &expected,
+                limiter,
+                &transport,
+                &job_cancel,
+            );
+            // O download pode já ter terminado
+            let _ = tx.send((block, peer, res));
+        });
+        Job {
+            block,
+            peer,
+            started: Instant::now(),
+            reassigned: false,
+            cancel,
+        }
+    }
+
+    fn fetch_info(
+        &self,
+        name: &str,
+        peers: &PeersWithFile,
+    ) -> anyhow::Result<FileInfo> {
+        for addr in all_holders(peers) {
+            let info = PeerConn::connect(
+                addr,
+                PEER_TIMEOUT,
+                self.limiter.clone(),
+                &self.transport,
+            )
+            .map_err(anyhow::Error::from)
+            .and_then(|mut conn| conn.info(name));
+            match info.and_then(|info| check_info(info, peers)) {
+                Ok(Some(info)) => return Ok(info),
+                Ok(None) => println!("{} doesn't have {}", addr, name),
+                Err(e) => println!("{}: {}", addr, e),
+            }
+        }
+        bail!("No peer could describe {}", name)
+    }
+
+    /// Mede em paralelo o RTT dos peers sem medições recentes. Um peer
+    /// que não responde fica com o RTT do timeout, e passa para o fim.
+    fn probe(&self, addrs: &[SocketAddr]) {
+        thread::scope(|s| {
+            for &addr in addrs {
+                if !self.peers.needs_probe(addr, PROBE_INTERVAL) {
+                    continue;
+                }
+                s.spawn(move || {
+                    let limiter = self.limiter.clone();
+                    let rtt = PeerConn::connect(
+                        addr,
+                        PROBE_TIMEOUT,
+                        limiter,
+                        &self.transport,
+                    )
+                    .map_err(anyhow::Error::from)
+                    .and_then(|mut conn| conn.ping());
+                    match rtt {
+                        Ok(rtt) => self.peers.record_rtt(addr, rtt),
+                        Err(e) => {
+                            println!("probe {}: {}", addr, e);
+                            self.peers.record_rtt(addr, PROBE_TIMEOUT);
+                        }
+                    }
+                });
+            }
+        });
+    }
+}
+
+fn no_peer(block: u32, name: &str) -> anyhow::Error {
+    anyhow!("No peer could provide block {} of {}", block, name)
+}
+
+/// A informação vem de um peer: os digests têm de ser um por bloco, e os
+/// blocos os que o tracker indica, antes de serem usados.
+fn check_info(
+    info: Option<FileInfo>,
+    peers: &PeersWithFile,
+) -> anyhow::Result<Option<FileInfo>> {
+    let Some(info) = info else {
+        return Ok(None);
+    };
+    if info.block_size == 0 || info.block_size > BLOCK_SIZE {
+        bail!("invalid block size {}", info.block_size);
+    }
+    // Em u64: um tamanho enorme não pode dar a volta ao u32
+    let blocks = info.f_size.div_ceil(info.block_size as u64);
+    if blocks != peers.n_blocks as u64 {
+        bail!(
+            "{} blocks in {} bytes, the tracker has {}",
+            blocks,
+            info.f_size,
+            peers.n_blocks
+        );
+    }
+    if info.digests.len() != info.n_blocks() as usize {
+        let (digests, blocks) = (info.digests.len(), info.n_blocks());
+        bail!("{} digests for {} blocks", digests, blocks);
+    }
+    Ok(Some(info))
+}
+
+/// Peers que têm o bloco, começando pelos que têm o ficheiro completo.
+fn holders(peers: &PeersWithFile, block: u32) -> Vec<SocketAddr> {
+    let mut addrs: Vec<SocketAddr> =
+        peers.peers_with_file.iter().copied().collect();
+    for addr in peers.peers_with_blocks.get(&block).into_iter().flatten() {
+        if !addrs.contains(addr) {
+            addrs.push(*addr);
+        }
+    }
+    addrs
+}
+
+/// Peers com o bloco que ainda não falharam demasiadas vezes.
+fn usable(
+    peers: &PeersWithFile,
+    block: u32,
+    failures: &HashMap<SocketAddr, u32>,
+) -> Vec<SocketAddr> {
+    holders(peers, block)
+        .into_iter()
+        .filter(|addr| {
+            failures.get(addr).copied().unwrap_or(0) < MAX_PEER_FAILURES
+        })
+        .collect()
+}
+
+fn all_holders(peers: &PeersWithFile) -> Vec<SocketAddr> {
+    let mut addrs = holders(peers, 0);
+    for addr in peers.peers_with_blocks.values().flatten() {
+        if !addrs.contains(addr) {
+            addrs.push(*addr);
+        }
+    }
+    addrs
+}
+
+fn fetch_block(
+    name: &str,
+    block: u32,
+    peer: SocketAddr,
+    expected: &Digest,
+    limiter: Arc<RateLimiter>,
+    transport: &Transport,
+    cancel: &Cancel,
+) -> anyhow::Result<Vec<u8>> {
+    if cancel.is_cancelled() {
+        bail!("cancelled");
+    }
+    let mut conn = PeerConn::connect(peer, PEER_TIMEOUT, limiter, transport)?;
+    *cancel.conn.lock().unwrap() = Some(conn.canceller()?);
+    // Pode ter sido cancelado enquanto a ligação era feita
+    if cancel.is_cancelled() {
+        bail!("cancelled");
+    }
+    match conn.block(name, block)? {
+        Some(data) if digest(&data) == *expected => Ok(data),
+        Some(_) => bail!("bad digest"),
+        None => bail!("block not available"),
+    }
+}
@@ -0,0 +1,75 @@
+//! Pontos de entrada dos alvos de fuzzing (`fuzz/`), também usados nos
+//! testes de propriedades. Só existem com a feature `fuzz`, que o `fuzz/`
+//! e os testes ativam.
+//!
+//! Cada um descodifica bytes arbitrários, o que só pode falhar com um
+//! erro, e volta a codificar o que for aceite (`Encode`): uma asserção
+//! que falhe é um bug do codec.
+use crate::codec::{Decode, Encode};
+use crate::file_meta::FileMeta;
+use crate::fstp::FstpMessage;
+use crate::load::{NodeLoad, LOAD_LEN};
+use crate::peers_with_blocks::PeersWithFile;
+
+/// Uma mensagem aceite volta a dar exatamente os mesmos bytes.
+pub fn fstp_message(bytes: &[u8]) {
+    let Ok(msg) = FstpMessage::from_bytes(bytes) else {
+        return;
+    };
+    let len = 3 + msg.header.data_size as usize;
+    assert_eq!(msg.encoded_len(), len);
+    let buf = msg.to_vec().expect("encoding a decoded message");
+    assert_eq!(&buf[..], &bytes[..len]);
+}
+
+/// Os metadados aceites sobrevivem a uma nova codificação. Os bytes
+/// podem mudar nos bits do bitmap além de `blocks_len`, que são
+/// ignorados.
+pub fn file_meta(bytes: &[u8]) {
+    let Ok((len, meta)) = FileMeta::from_bytes(bytes) else {
+        return;
+    };
+    assert!(len <= bytes.len());
+    assert_eq!(meta.encoded_len(), len);
+    let buf = meta.to_vec().expect("encoding decoded metadata");
+    assert_eq!(buf.len(), len);
+    let (again_len, again) =
+        FileMeta::decode(&buf).expect("decoding encoded metadata");
+    assert_eq!(again_len, len);
+    assert_same_meta(&meta, &again);
+}
+
+/// As listas aceites sobrevivem a uma nova codificação. A ordem dos
+/// peers e os repetidos podem mudar os bytes, mas não os conjuntos.
+pub fn peers_with_file(bytes: &[u8]) {
+    let Ok(peers) = PeersWithFile::from_bytes(bytes) else {
+        return;
+    };
+    let buf = peers.to_vec().expect("encoding a decoded peer list");
+    assert_eq!(buf.len(), peers.encoded_len());
+    assert!(buf.len() <= bytes.len());
+    let (len, again) =
+        PeersWithFile::decode(&buf).expect("decoding an encoded peer list");
+    assert_eq!(len, buf.len());
+    assert_eq!(again.n_blocks, peers.n_blocks);
+    assert_eq!(again.peers_with_file, peers.peers_with_file);
+    assert_eq!(again.peers_with_blocks, peers.peers_with_blocks);
+}
+
+pub fn node_load(bytes: &[u8]) {
+    let Ok(load) = NodeLoad::from_bytes(bytes) else {
+        return;
+    };
+    assert_eq!(&load.to_bytes()[..], &bytes[..LOAD_LEN]);
+}
+
+/// `FileMeta` só compara os nomes; aqui contam todos os campos.
+pub fn assert_same_meta(a: &FileMeta, b: &FileMeta) {
+    assert_eq!(a.f_size, b.f_size);
+    assert_eq!(a.has_full_file, b.has_full_file);
+    assert_eq!(a.block_size, b.block_size);
+    assert_eq!(a.blocks_len, b.blocks_len);
+    assert_eq!(a.name_len, b.name_len);
+    assert_eq!(a.blocks, b.blocks);
+    assert_eq!(a.name, b.name);
+}
@@ -1,9 +1,27 @@
 #![allow(dead_code)]
 #![feature(ip_bits)]
 
+pub mod codec;
+pub mod config;
+pub mod congestion;
+pub mod download;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
+pub mod load;
+pub mod peer_stats;
+pub mod pool;
+pub mod ranking;
+pub mod ratelimit;
+pub mod reliable;
+pub mod store;
+pub mod tracker_client;
+pub mod tracker_server;
+pub mod tracker_state;
+pub mod transfer;
+
 //TODO: Cenas de DNS
 pub mod fstp {
-    use anyhow::bail;
+    use crate::codec::{check_space, CodecError, Decode, Encode, Reader};
     #[derive(Debug)]
     pub struct FstpMessage<'a> {
         pub header: FstpHeader,
@@ -16,37 +34,102 @@ pub mod fstp {
         pub data_size: u16,
     }
 
-    #[derive(Debug)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum Flag {
         Ok,
         Add,
         List,
         File,
+        // Carga do nó (`load::NodeLoad`), sem resposta
+        Stats,
+        // Ficheiros que o nó deixou de partilhar, separados por vírgulas,
+        // sem resposta
+        Remove,
+        // Porta (u16) onde o nó serve blocos, sem resposta
+        Port,
+        // Resposta a um pedido válido que o tracker não consegue
+        // satisfazer (p.e. grande demais para uma mensagem), com o motivo
+        // em texto
+        Error,
     }
 
-    impl<'a> FstpMessage<'a> {
-        pub fn as_bytes(self, buf: &mut [u8]) -> anyhow::Result<usize> {
-            let flag = &self.header.flag;
-            buf[0] = flag.to_bytes();
-            let b_data_size: [u8; 2] = self.header.data_size.to_be_bytes();
-            let data_size = u16::from_be_bytes(b_data_size).try_into().unwrap();
-            buf[1..3].copy_from_slice(&b_data_size);
-            if let Some(data) = self.data {
-                buf[3..3 + data.len()].copy_from_slice(&data[..data_size]);
+    impl Encode for FstpMessage<'_> {
+        fn encoded_len(&self) -> usize {
+            3 + self.data.map_or(0, <[u8]>::len)
+        }
+
+        fn as_bytes(&self, buf: &mut [u8]) -> Result<usize, CodecError> {
+            let data = self.data.unwrap_or(&[]);
+            if data.len() != self.header.data_size as usize {
+                return Err(CodecError::InvalidValue {
+                    field: "data_size",
+                    value: self.header.data_size as u64,
+                });
+            }
+            let needed = 3 + data.len();
+            check_space(buf, needed)?;
+            buf[0] = self.header.flag.to_bytes();
+            buf[1..3].copy_from_slice(&self.header.data_size.to_be_bytes());
+            buf[3..needed].copy_from_slice(data);
+            Ok(needed)
+        }
+    }
+
+    /// Acrescenta a `buf` uma mensagem `flag` com `data` codificado logo a
+    /// seguir ao cabeçalho, sem passar por um buffer intermédio.
+    pub fn encode_message<T: Encode + ?Sized>(
+        flag: Flag,
+        data: &T,
+        buf: &mut Vec<u8>,
+    ) -> Result<usize, CodecError> {
+        let len = data.encoded_len();
+        let Ok(data_size) = u16::try_from(len) else {
+            return Err(CodecError::TooLarge {
+                len,
+                max: u16::MAX as usize,
+            });
+        };
+        let start = buf.len();
+        buf.reserve(3 + len);
+        buf.push(flag.to_bytes());
+        buf.extend_from_slice(&data_size.to_be_bytes());
+        match data.encode(buf) {
+            Ok(_) => Ok(3 + len),
+            Err(e) => {
+                buf.truncate(start);
+                Err(e)
             }
-            Ok(3 + self.data.unwrap_or(&[]).len())
         }
+    }
 
-        pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<FstpMessage> {
-            let (header, data) = bytes.split_at(3);
-            let flag = Flag::from_bytes(&header[0])?;
-            let b_data_size =
-                u16::from_be_bytes(header[1..3].try_into().unwrap());
-            let data = if b_data_size == 0 {
-                None
-            } else {
-                Some(&data[0..(b_data_size as usize)])
+    impl<'a> Decode<'a> for FstpMessage<'a> {
+        fn decode(bytes: &'a [u8]) -> Result<(usize, Self), CodecError> {
+            let msg = Self::from_bytes(bytes)?;
+            Ok((3 + msg.header.data_size as usize, msg))
+        }
+    }
+
+    impl<'a> FstpMessage<'a> {
+        /// Mensagem `flag` com `data`, que têm de caber num u16.
+        pub fn new(flag: Flag, data: &'a [u8]) -> Result<Self, CodecError> {
+            let Ok(data_size) = u16::try_from(data.len()) else {
+                let (len, max) = (data.len(), u16::MAX as usize);
+                return Err(CodecError::TooLarge { len, max });
             };
+            Ok(FstpMessage {
+                header: FstpHeader { flag, data_size },
+                data: (!data.is_empty()).then_some(data),
+            })
+        }
+
+        pub fn from_bytes(
+            bytes: &'a [u8],
+        ) -> Result<FstpMessage<'a>, CodecError> {
+            let mut r = Reader::new(bytes);
+            let flag = Flag::from_bytes(&r.u8("flag")?)?;
+            let b_data_size = r.u16("data_size")?;
+            let data = r.bytes(b_data_size as usize, "data")?;
+            let data = if b_data_size == 0 { None } else { Some(data) };
             Ok(FstpMessage {
                 header: FstpHeader {
                     flag,
@@ -58,93 +141,166 @@ pub mod fstp {
     }
 
     impl Flag {
-        fn to_bytes(&self) -> u8 {
+        fn to_bytes(self) -> u8 {
             match self {
                 Self::Ok => 1u8,
                 Self::Add => 2u8,
                 Self::List => 3u8,
                 Self::File => 4u8,
+                Self::Stats => 5u8,
+                Self::Remove => 6u8,
+                Self::Port => 7u8,
+                Self::Error => 8u8,
             }
         }
 
-        fn from_bytes(byte: &u8) -> anyhow::Result<Self> {
+        fn from_bytes(byte: &u8) -> Result<Self, CodecError> {
             match byte {
                 1 => Ok(Self::Ok),
                 2 => Ok(Self::Add),
                 3 => Ok(Self::List),
                 4 => Ok(Self::File),
-                _ => bail!("Flag inválida"),
+                5 => Ok(Self::Stats),
+                6 => Ok(Self::Remove),
+                7 => Ok(Self::Port),
+                8 => Ok(Self::Error),
+                other => Err(CodecError::InvalidFlag(*other)),
             }
         }
     }
 }
 
 pub mod file_meta {
+    use crate::codec::{check_space, utf8, CodecError, Decode, Encode, Reader};
     use bitvec::prelude::*;
     use std::hash::{Hash, Hasher};
-    use std::io::{Read, Write};
-    use std::str::from_utf8;
+
+    /// Tamanho dos blocos em que os ficheiros são divididos.
+    pub const BLOCK_SIZE: u32 = 256 * 1024;
+    /// f_size + has_full_file + block_size + blocks_len + name_len
+    const HEADER_LEN: usize = 19;
 
     #[derive(Debug, Clone)]
     pub struct FileMeta {
         pub f_size: u64,
         pub has_full_file: bool,
+        pub block_size: u32,
         pub blocks_len: u32,
         pub name_len: u16,
         pub blocks: BitVec<u8, Msb0>,
         pub name: String,
     }
 
+    /// Número de blocos de `block_size` bytes necessários para `f_size`.
+    pub fn n_blocks(f_size: u64, block_size: u32) -> u32 {
+        f_size.div_ceil(block_size as u64) as u32
+    }
+
+    /// Bytes ocupados pelo bitmap de `blocks_len` blocos.
+    pub fn bitmap_len(blocks_len: u32) -> usize {
+        (blocks_len as usize).div_ceil(8)
+    }
+
     impl FileMeta {
-        pub fn as_bytes(self, buf: &mut [u8]) -> anyhow::Result<usize> {
-            let blocks_len = self.blocks_len as usize;
+        /// Metadados de um ficheiro completo, com todos os blocos marcados.
+        pub fn full(name: String, f_size: u64, block_size: u32) -> Self {
+            let blocks_len = n_blocks(f_size, block_size);
+            FileMeta {
+                f_size,
+                has_full_file: true,
+                block_size,
+                blocks_len,
+                name_len: name.len() as u16,
+                blocks: bitvec![u8, Msb0; 1; blocks_len as usize],
+                name,
+            }
+        }
+
+        /// Devolve também os bytes lidos, para ler vários metadados
+        /// seguidos.
+        pub fn from_bytes(bytes: &[u8]) -> Result<(usize, Self), CodecError> {
+            let mut r = Reader::new(bytes);
+            let f_size = r.u64("f_size")?;
+            let has_full_file = match r.u8("has_full_file")? {
+                0 => false,
+                1 => true,
+                other => {
+                    return Err(CodecError::InvalidValue {
+                        field: "has_full_file",
+                        value: other as u64,
+                    })
+                }
+            };
+            let block_size = r.u32("block_size")?;
+            let blocks_len = r.u32("blocks_len")?;
+            let name_len = r.u16("name_len")?;
+            let bitmap = r.bytes(bitmap_len(blocks_len), "block bitmap")?;
+            let mut blocks = BitVec::<u8, Msb0>::from_slice(bitmap);
+            blocks.truncate(blocks_len as usize);
+            let name = r.bytes(name_len as usize, "file name")?;
+            let name = String::from(utf8(name, "file name")?);
+            let fm = FileMeta {
+                f_size,
+                has_full_file,
+                block_size,
+                blocks_len,
+                name_len,
+                blocks,
+                name,
+            };
+            Ok((r.pos(), fm))
+        }
+    }
+
+    impl Encode for FileMeta {
+        fn encoded_len(&self) -> usize {
+            HEADER_LEN + bitmap_len(self.blocks_len) + self.name.len()
+        }
+
+        fn as_bytes(&self, buf: &mut [u8]) -> Result<usize, CodecError> {
+            let bitmap_len = bitmap_len(self.blocks_len);
             let b_f_size = self.f_size.to_be_bytes();
             let has_ffile = self.has_full_file;
             let b_has_ff = if has_ffile { [1u8] } else { [0u8] };
+            let b_block_size = self.block_size.to_be_bytes();
             let b_blocks_len = self.blocks_len.to_be_bytes();
             let b_name_len = self.name_len.to_be_bytes();
-            let mut b_blocks_buff = [0u8; 1000];
-            self.blocks.clone().read(&mut b_blocks_buff)?;
             let b_name = self.name.as_bytes();
+            if b_name.len() != self.name_len as usize {
+                return Err(CodecError::InvalidValue {
+                    field: "name_len",
+                    value: self.name_len as u64,
+                });
+            }
+            let name_start = HEADER_LEN + bitmap_len;
+            let needed = name_start + b_name.len();
+            check_space(buf, needed)?;
 
             buf[..8].copy_from_slice(&b_f_size);
             buf[8..9].copy_from_slice(&b_has_ff);
-            buf[9..13].copy_from_slice(&b_blocks_len);
-            buf[13..15].copy_from_slice(&b_name_len);
-            buf[15..15 + blocks_len]
-                .copy_from_slice(&b_blocks_buff[..blocks_len]);
-            buf[15 + blocks_len..15 + blocks_len + b_name.len()]
-                .copy_from_slice(b_name);
-            Ok(15 + blocks_len as usize + b_name.len())
+            buf[9..13].copy_from_slice(&b_block_size);
+            buf[13..17].copy_from_slice(&b_blocks_len);
+            buf[17..HEADER_LEN].copy_from_slice(&b_name_len);
+            // Bits além de blocks_len ficam a 0
+            let b_blocks = &mut buf[HEADER_LEN..name_start];
+            b_blocks.fill(0);
+            let n_bits = self.blocks_len as usize;
+            for (i, bit) in self.blocks.iter().take(n_bits).enumerate() {
+                if *bit {
+                    b_blocks[i / 8] |= 0x80 >> (i % 8);
+                }
+            }
+            buf[name_start..needed].copy_from_slice(b_name);
+            Ok(needed)
         }
+    }
 
-        pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<(usize, Self)> {
-            let f_size = u64::from_be_bytes(bytes[0..8].try_into().unwrap());
-            let has_full_file = if bytes[8] == 1 { true } else { false };
-            let blocks_len =
-                u32::from_be_bytes(bytes[9..13].try_into().unwrap());
-            let name_len =
-                u16::from_be_bytes(bytes[13..15].try_into().unwrap());
-            let mut blocks = BitVec::<u8, Msb0>::new();
-            blocks.write(&bytes[15..15 + blocks_len as usize])?;
-            println!("bl:{},nl:{}", blocks_len, name_len);
-            let name = String::from(
-                from_utf8(
-                    &bytes[15..15 + blocks_len as usize + name_len as usize],
-                )
-                .unwrap(),
-            );
-            let fm = FileMeta {
-                f_size,
-                has_full_file,
-                blocks_len,
-                name_len,
-                blocks,
-                name,
-            };
-            Ok((15 + blocks_len as usize + name_len as usize, fm))
+    impl Decode<'_> for FileMeta {
+        fn decode(bytes: &[u8]) -> Result<(usize, Self), CodecError> {
+            Self::from_bytes(bytes)
         }
     }
+
     impl PartialEq for FileMeta {
         fn eq(&self, other: &Self) -> bool {
             self.name == other.name
@@ -160,14 +316,19 @@ pub mod file_meta {
 }
 
 pub mod peers_with_blocks {
+    use crate::codec::{check_space, CodecError, Decode, Encode, Reader};
     use std::collections::{HashMap, HashSet};
-    use std::net::{IpAddr, Ipv4Addr};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    /// Cada peer vai como `[ip u32][porta u16]`: a porta é a que o nó
+    /// anunciou ao tracker para servir blocos.
+    const PEER_LEN: usize = 6;
 
     #[derive(Debug)]
     pub struct PeersWithFile {
         pub n_blocks: u32,
-        pub peers_with_file: HashSet<IpAddr>,
-        pub peers_with_blocks: HashMap<u32, HashSet<IpAddr>>,
+        pub peers_with_file: HashSet<SocketAddr>,
+        pub peers_with_blocks: HashMap<u32, HashSet<SocketAddr>>,
     }
 
     impl PeersWithFile {
@@ -179,117 +340,93 @@ pub mod peers_with_blocks {
             }
         }
 
-        pub fn to_bytes(self, buf: &mut [u8]) -> u16 {
-            let mut offset = 0;
-            let p_w_f_len = self.peers_with_file.len() as u16;
-            let b_p_w_f_len = p_w_f_len.to_be_bytes();
-            let mut p_w_f_buf = [0u8; 400];
-            let p_w_f_buf_size =
-                Self::bin_p_w_f(self.peers_with_file, &mut p_w_f_buf);
-
-            let mut p_w_b_buf = [0u8; 1000];
-            let p_w_b_buf_size = Self::bin_p_w_b(
-                self.peers_with_blocks,
-                &mut p_w_b_buf,
-                self.n_blocks,
-            );
-
-            if p_w_f_len != 0 {
-                buf[0..2].copy_from_slice(&b_p_w_f_len);
-                buf[2..2 + p_w_f_buf_size]
-                    .copy_from_slice(&p_w_f_buf[..p_w_f_buf_size]);
-                offset += 2 + p_w_f_buf_size;
-                buf[offset..offset + p_w_b_buf_size]
-                    .copy_from_slice(&p_w_b_buf[..p_w_b_buf_size]);
-                offset += p_w_b_buf_size
+        pub fn from_bytes(bytes: &[u8]) -> Result<PeersWithFile, CodecError> {
+            let mut r = Reader::new(bytes);
+            let mut peers_with_file = HashSet::<SocketAddr>::new();
+            let mut peers_with_blocks =
+                HashMap::<u32, HashSet<SocketAddr>>::new();
+            let mut block_id: u32 = 0;
+            for _ in 0..r.u16("peer count")? {
+                peers_with_file.insert(read_peer(&mut r)?);
             }
-            offset as u16
-        }
 
-        fn bin_p_w_f(p_w_f: HashSet<IpAddr>, buf: &mut [u8]) -> usize {
-            let mut size = 0;
-            for (i, ip) in p_w_f.iter().enumerate() {
-                match ip {
-                    IpAddr::V4(ipv4) => {
-                        let b_ip = ipv4.to_bits().to_be_bytes();
-                        let lower: usize = i * 4;
-                        let upper = lower + 4;
-                        buf[lower..upper].copy_from_slice(&b_ip);
-                        size = upper;
-                    }
-                    _ => {}
+            while !r.is_empty() {
+                for _ in 0..r.u32("block peer count")? {
+                    let addr = read_peer(&mut r)?;
+                    peers_with_blocks.entry(block_id).or_default().insert(addr);
                 }
+                block_id += 1;
             }
-            size
+            Ok(PeersWithFile {
+                n_blocks: block_id,
+                peers_with_file,
+                peers_with_blocks,
+            })
         }
+    }
 
-        fn bin_p_w_b(
-            p_w_b: HashMap<u32, HashSet<IpAddr>>,
-            buf: &mut [u8],
-            n_blocks: u32,
-        ) -> usize {
-            let mut offset = 0;
-            for b_id in 0..n_blocks {
-                if let Some(ips_set) = p_w_b.get(&b_id) {
-                    buf[offset..offset + 4]
-                        .copy_from_slice(&ips_set.len().to_be_bytes());
-                    offset += 4;
-                    for ip in ips_set {
-                        match ip {
-                            IpAddr::V4(ipv4) => {
-                                let b_ip = ipv4.to_bits().to_be_bytes();
-                                buf[offset..offset + 4].copy_from_slice(&b_ip);
-                                offset += 4;
-                            }
-                            _ => {}
-                        }
-                    }
-                } else {
-                    buf[offset..offset + 4].copy_from_slice(&[0, 0, 0, 0]);
-                    offset += 4;
-                }
-            }
-            offset
+    impl Encode for PeersWithFile {
+        fn encoded_len(&self) -> usize {
+            let with_blocks: usize = (0..self.n_blocks)
+                .map(|b_id| {
+                    let peers = self.peers_with_blocks.get(&b_id);
+                    4 + peers.map_or(0, |p| ipv4(p).count() * PEER_LEN)
+                })
+                .sum();
+            2 + ipv4(&self.peers_with_file).count() * PEER_LEN + with_blocks
         }
 
-        pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<PeersWithFile> {
-            let n_ips_w_f = u16::from_be_bytes(bytes[0..2].try_into().unwrap());
-            let mut offset: usize = 2;
-            let mut peers_with_file = HashSet::<IpAddr>::new();
-            let mut peers_with_blocks = HashMap::<u32, HashSet<IpAddr>>::new();
-            let mut block_id: u32 = 0;
-            for _ in 0..n_ips_w_f {
-                let ip_bits = u32::from_be_bytes(
-                    bytes[offset..offset + 4].try_into().unwrap(),
-                );
-                offset += 4;
-                let ip = IpAddr::V4(Ipv4Addr::from_bits(ip_bits));
-                peers_with_file.insert(ip);
-            }
+        fn as_bytes(&self, buf: &mut [u8]) -> Result<usize, CodecError> {
+            let n_peers = ipv4(&self.peers_with_file).count();
+            let Ok(b_n_peers) = u16::try_from(n_peers) else {
+                let (len, max) = (n_peers, u16::MAX as usize);
+                return Err(CodecError::TooLarge { len, max });
+            };
+            check_space(buf, self.encoded_len())?;
 
-            while offset < bytes.len() {
-                let n_ips_w_b = u32::from_be_bytes(
-                    bytes[offset..offset + 4].try_into().unwrap(),
-                );
+            buf[0..2].copy_from_slice(&b_n_peers.to_be_bytes());
+            let mut offset = 2;
+            for addr in ipv4(&self.peers_with_file) {
+                offset += bin_peer(addr, &mut buf[offset..]);
+            }
+            let none = HashSet::new();
+            for b_id in 0..self.n_blocks {
+                let peers = self.peers_with_blocks.get(&b_id).unwrap_or(&none);
+                let n_peers = ipv4(peers).count() as u32;
+                buf[offset..offset + 4].copy_from_slice(&n_peers.to_be_bytes());
                 offset += 4;
-                for _ in 0..n_ips_w_b {
-                    let ip_bits = u32::from_be_bytes(
-                        bytes[offset..offset + 4].try_into().unwrap(),
-                    );
-                    offset += 4;
-                    if let None = peers_with_blocks.get(&block_id) {
-                        peers_with_blocks.insert(block_id, HashSet::new());
-                    }
-                    let ip = IpAddr::V4(Ipv4Addr::from_bits(ip_bits));
-                    peers_with_blocks.get_mut(&block_id).unwrap().insert(ip);
+                for addr in ipv4(peers) {
+                    offset += bin_peer(addr, &mut buf[offset..]);
                 }
-                block_id += 1;
             }
-            Ok(PeersWithFile {
-                n_blocks: block_id,
-                peers_with_file,
-                peers_with_blocks,
-            })
+            Ok(offset)
+        }
+    }
+
+    impl Decode<'_> for PeersWithFile {
+        /// A lista vai até ao fim da mensagem: usa sempre todos os bytes.
+        fn decode(bytes: &[u8]) -> Result<(usize, Self), CodecError> {
+            Ok((bytes.len(), Self::from_bytes(bytes)?))
         }
     }
+
+    // Só há endereços IPv4 no protocolo; os outros não são escritos
+    fn ipv4(peers: &HashSet<SocketAddr>) -> impl Iterator<Item = &SocketAddr> {
+        peers.iter().filter(|addr| addr.is_ipv4())
+    }
+
+    fn bin_peer(addr: &SocketAddr, buf: &mut [u8]) -> usize {
+        let IpAddr::V4(ipv4) = addr.ip() else {
+            return 0;
+        };
+        buf[..4].copy_from_slice(&ipv4.to_bits().to_be_bytes());
+        buf[4..PEER_LEN].copy_from_slice(&addr.port().to_be_bytes());
+        PEER_LEN
+    }
+
+    fn read_peer(r: &mut Reader) -> Result<SocketAddr, CodecError> {
+        let ip = Ipv4Addr::from_bits(r.u32("peer address")?);
+        let port = r.u16("peer port")?;
+        Ok(SocketAddr::new(IpAddr::V4(ip), port))
+    }
 }
@@ -0,0 +1,143 @@
+//! Carga de upload de um nó, reportada periodicamente ao tracker para
+//! este evitar mandar mais downloads para os nós mais ocupados.
+//!
+//! Mensagem `Stats`: `[active u32][queued u32][throughput u64]`.
+use crate::codec::{CodecError, Reader};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+pub const LOAD_LEN: usize = 16;
+/// Pedidos (em curso ou em espera) com que um nó fica a meio da escala
+/// de carga.
+const HALF_LOAD: f64 = 4.0;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NodeLoad {
+    /// Respostas a ser enviadas.
+    pub active_uploads: u32,
+    /// Pedidos recebidos ainda sem resposta a ser enviada.
+    pub queued_requests: u32,
+    /// Upload recente, em bytes/s.
+    pub throughput: u64,
+}
+
+impl NodeLoad {
+    pub fn to_bytes(&self) -> [u8; LOAD_LEN] {
+        let mut buf = [0u8; LOAD_LEN];
+        buf[0..4].copy_from_slice(&self.active_uploads.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.queued_requests.to_be_bytes());
+        buf[8..16].copy_from_slice(&self.throughput.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut r = Reader::new(bytes);
+        Ok(NodeLoad {
+            active_uploads: r.u32("active_uploads")?,
+            queued_requests: r.u32("queued_requests")?,
+            throughput: r.u64("throughput")?,
+        })
+    }
+
+    /// Carga entre 0 (livre) e 1 (saturado).
+    pub fn score(&self) -> f64 {
+        // Em f64 antes de somar: os valores vêm do nó, sem limite
+        let requests = self.active_uploads as f64 + self.queued_requests as f64;
+        requests / (requests + HALF_LOAD)
+    }
+}
+
+/// Contadores do servidor de blocos de um nó.
+#[derive(Debug)]
+pub struct UploadStats {
+    active: AtomicU32,
+    queued: AtomicU32,
+    bytes: AtomicU64,
+    // Bytes e instante do último relatório, para o débito recente
+    last: Mutex<(u64, Instant)>,
+}
+
+impl Default for UploadStats {
+    fn default() -> Self {
+        UploadStats {
+            active: AtomicU32::new(0),
+            queued: AtomicU32::new(0),
+            bytes: AtomicU64::new(0),
+            last: Mutex::new((0, Instant::now())),
+        }
+    }
+}
+
+impl UploadStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Regista um pedido recebido, em espera até `Upload::start`.
+    pub fn upload(&self) -> Upload<'_> {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        Upload {
+            stats: self,
+            started: false,
+        }
+    }
+
+    /// Respostas a ser enviadas e pedidos em espera.
+    pub fn uploads(&self) -> (u32, u32) {
+        (
+            self.active.load(Ordering::Relaxed),
+            self.queued.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Estado atual, com o débito desde o relatório anterior.
+    pub fn report(&self) -> NodeLoad {
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let mut last = self.last.lock().unwrap();
+        let secs = last.1.elapsed().as_secs_f64();
+        let throughput = if secs > 0.0 {
+            ((bytes - last.0) as f64 / secs) as u64
+        } else {
+            0
+        };
+        *last = (bytes, Instant::now());
+        NodeLoad {
+            active_uploads: self.active.load(Ordering::Relaxed),
+            queued_requests: self.queued.load(Ordering::Relaxed),
+            throughput,
+        }
+    }
+}
+
+/// Pedido a ser servido; sai dos contadores quando é largado.
+pub struct Upload<'a> {
+    stats: &'a UploadStats,
+    started: bool,
+}
+
+impl Upload<'_> {
+    /// A resposta começou a ser enviada.
+    pub fn start(&mut self) {
+        if !self.started {
+            self.started = true;
+            self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+            self.stats.active.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.stats.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Upload<'_> {
+    fn drop(&mut self) {
+        let counter = if self.started {
+            &self.stats.active
+        } else {
+            &self.stats.queued
+        };
+        counter.fetch_sub(1, Ordering::Relaxed);
+    }
+}
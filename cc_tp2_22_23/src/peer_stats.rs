@@ -0,0 +1,125 @@
+//! Medições de latência e débito de cada peer, para escolher de quem
+//! descarregar cada bloco.
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Peso de cada nova amostra nas médias móveis (como o SRTT do TCP).
+const ALPHA: f64 = 0.125;
+/// Valores assumidos enquanto não houver medições, otimistas para que os
+/// peers novos também sejam experimentados.
+const DEFAULT_RTT: Duration = Duration::from_millis(50);
+const DEFAULT_THROUGHPUT: f64 = 1024.0 * 1024.0;
+
+#[derive(Debug, Clone, Default)]
+pub struct PeerStats {
+    pub rtt: Option<Duration>,
+    /// bytes/s
+    pub throughput: Option<f64>,
+    pub last_probe: Option<Instant>,
+}
+
+impl PeerStats {
+    /// Tempo estimado para receber `bytes` deste peer.
+    pub fn cost(&self, bytes: usize) -> Duration {
+        let rtt = self.rtt.unwrap_or(DEFAULT_RTT);
+        let throughput = self.throughput.unwrap_or(DEFAULT_THROUGHPUT);
+        rtt + Duration::from_secs_f64(bytes as f64 / throughput)
+    }
+}
+
+fn ewma(old: Option<f64>, sample: f64) -> f64 {
+    match old {
+        Some(old) => (1.0 - ALPHA) * old + ALPHA * sample,
+        None => sample,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PeerTable {
+    peers: Mutex<HashMap<SocketAddr, PeerStats>>,
+}
+
+impl PeerTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, peer: SocketAddr) -> PeerStats {
+        let peers = self.peers.lock().unwrap();
+        peers.get(&peer).cloned().unwrap_or_default()
+    }
+
+    pub fn all(&self) -> Vec<(SocketAddr, PeerStats)> {
+        let peers = self.peers.lock().unwrap();
+        peers.iter().map(|(addr, s)| (*addr, s.clone())).collect()
+    }
+
+    pub fn record_rtt(&self, peer: SocketAddr, rtt: Duration) {
+        let mut peers = self.peers.lock().unwrap();
+        let stats = peers.entry(peer).or_default();
+        let old = stats.rtt.map(|d| d.as_secs_f64());
+        stats.rtt = Some(Duration::from_secs_f64(ewma(old, rtt.as_secs_f64())));
+        stats.last_probe = Some(Instant::now());
+    }
+
+    pub fn record_transfer(
+        &self,
+        peer: SocketAddr,
+        bytes: usize,
+        took: Duration,
+    ) {
+        if took.is_zero() {
+            return;
+        }
+        let sample = bytes as f64 / took.as_secs_f64();
+        let mut peers = self.peers.lock().unwrap();
+        let stats = peers.entry(peer).or_default();
+        stats.throughput = Some(ewma(stats.throughput, sample));
+    }
+
+    /// Se a última sonda a `peer` tem mais de `max_age` (ou nunca houve).
+    pub fn needs_probe(&self, peer: SocketAddr, max_age: Duration) -> bool {
+        let peers = self.peers.lock().unwrap();
+        match peers.get(&peer).and_then(|s| s.last_probe) {
+            Some(t) => t.elapsed() > max_age,
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_sample_is_taken_as_is_then_averaged() {
+        assert_eq!(ewma(None, 80.0), 80.0);
+        assert_eq!(ewma(Some(80.0), 160.0), 90.0);
+        assert_eq!(ewma(Some(80.0), 80.0), 80.0);
+    }
+
+    #[test]
+    fn measurements_move_the_cost() {
+        let table = PeerTable::new();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 9090));
+        // Sem medições, o peer é tão bom como os valores por omissão
+        let unknown = table.get(peer).cost(1024 * 1024);
+        assert_eq!(unknown, DEFAULT_RTT + Duration::from_secs(1));
+        assert!(table.needs_probe(peer, Duration::from_secs(30)));
+
+        table.record_rtt(peer, Duration::from_millis(10));
+        table.record_rtt(peer, Duration::from_millis(90));
+        let rtt = table.get(peer).rtt.unwrap();
+        assert_eq!(rtt, Duration::from_millis(20));
+        assert!(!table.needs_probe(peer, Duration::from_secs(30)));
+
+        table.record_transfer(peer, 1000, Duration::from_secs(1));
+        table.record_transfer(peer, 1000, Duration::ZERO);
+        let stats = table.get(peer);
+        assert_eq!(stats.throughput, Some(1000.0));
+        let cost = stats.cost(2000);
+        assert_eq!(cost, Duration::from_millis(2020));
+    }
+}
@@ -0,0 +1,56 @@
+//! Buffers reutilizáveis, para o tracker não alocar um `Vec` por cada
+//! mensagem recebida e por cada resposta.
+//!
+//! Um buffer tirado da pool (`get`) vem vazio, mas com a capacidade que
+//! já tinha; `put` devolve-o. Os buffers que cresceram demais (p.e. uma
+//! resposta com muitos peers) não voltam, para a pool não ficar com a
+//! memória do pior caso.
+use std::sync::{Mutex, PoisonError};
+
+/// Buffers guardados, no máximo, quando ninguém os está a usar.
+const MAX_POOLED: usize = 256;
+/// Capacidade acima da qual um buffer é largado em vez de guardado.
+const MAX_CAPACITY: usize = 64 * 1024;
+
+#[derive(Debug, Default)]
+pub struct BufferPool {
+    bufs: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Um buffer vazio, reutilizado se houver algum livre.
+    pub fn get(&self) -> Vec<u8> {
+        self.bufs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop()
+            .unwrap_or_default()
+    }
+
+    pub fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0 || buf.capacity() > MAX_CAPACITY {
+            return;
+        }
+        buf.clear();
+        let mut bufs = self.bufs.lock().unwrap_or_else(PoisonError::into_inner);
+        if bufs.len() < MAX_POOLED {
+            bufs.push(buf);
+        }
+    }
+
+    /// Buffers livres.
+    pub fn len(&self) -> usize {
+        self.bufs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
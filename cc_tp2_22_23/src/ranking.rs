@@ -0,0 +1,166 @@
+//! Escolha, no tracker, dos peers devolvidos para cada ficheiro.
+//!
+//! Cada peer recebe uma pontuação: uma soma pesada de ser seeder, da
+//! carga que reportou, da proximidade ao nó que pediu (prefixo comum dos
+//! endereços) e de um valor aleatório, que espalha os downloads por
+//! peers equivalentes. Só os melhores são devolvidos.
+use anyhow::bail;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Pesos de cada critério na pontuação.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RankingPolicy {
+    pub seeder: f64,
+    pub load: f64,
+    pub proximity: f64,
+    pub random: f64,
+}
+
+impl RankingPolicy {
+    pub const BALANCED: RankingPolicy = RankingPolicy {
+        seeder: 1.0,
+        load: 1.0,
+        proximity: 0.5,
+        random: 0.5,
+    };
+    pub const RANDOM: RankingPolicy = RankingPolicy {
+        seeder: 0.0,
+        load: 0.0,
+        proximity: 0.0,
+        random: 1.0,
+    };
+    pub const NEAREST: RankingPolicy = RankingPolicy {
+        seeder: 0.5,
+        load: 0.5,
+        proximity: 2.0,
+        random: 0.1,
+    };
+}
+
+impl Default for RankingPolicy {
+    fn default() -> Self {
+        RankingPolicy::BALANCED
+    }
+}
+
+impl FromStr for RankingPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "balanced" => Ok(RankingPolicy::BALANCED),
+            "random" => Ok(RankingPolicy::RANDOM),
+            "nearest" => Ok(RankingPolicy::NEAREST),
+            _ => bail!("Unknown ranking policy: {}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    /// Endereço onde o nó serve os blocos.
+    pub addr: SocketAddr,
+    pub seeder: bool,
+    /// Carga reportada pelo nó, de 0 (livre) a 1 (saturado).
+    pub load: f64,
+}
+
+/// Ordena os candidatos, do melhor para o pior, para `requester`.
+pub fn rank(
+    requester: IpAddr,
+    candidates: &[Candidate],
+    policy: &RankingPolicy,
+) -> Vec<SocketAddr> {
+    let mut rng = Rng::new();
+    let mut scored: Vec<(f64, SocketAddr)> = candidates
+        .iter()
+        .map(|c| {
+            let score = policy.seeder * f64::from(u8::from(c.seeder))
+                + policy.load * (1.0 - c.load.clamp(0.0, 1.0))
+                + policy.proximity * proximity(requester, c.addr.ip())
+                + policy.random * rng.next();
+            (score, c.addr)
+        })
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored.into_iter().map(|(_, addr)| addr).collect()
+}
+
+/// Escolhe até `max_peers` peers para o ficheiro, pela ordem de `rank`.
+/// Os blocos de cada um ficam com até `max_per_block` peers; um bloco
+/// que nenhum dos escolhidos tenha recebe os melhores de fora, para a
+/// resposta cobrir sempre todos os blocos disponíveis.
+///
+/// `blocks` diz que blocos tem cada peer sem o ficheiro completo.
+pub fn select(
+    ranked: &[SocketAddr],
+    seeders: &HashSet<SocketAddr>,
+    blocks: &HashMap<SocketAddr, Vec<u32>>,
+    max_peers: usize,
+    max_per_block: usize,
+) -> (HashSet<SocketAddr>, HashMap<u32, HashSet<SocketAddr>>) {
+    let chosen = &ranked[..ranked.len().min(max_peers)];
+    let with_file: HashSet<SocketAddr> = chosen
+        .iter()
+        .filter(|addr| seeders.contains(addr))
+        .copied()
+        .collect();
+
+    let mut with_blocks: HashMap<u32, HashSet<SocketAddr>> = HashMap::new();
+    let mut add = |addr: SocketAddr, only_uncovered: bool| {
+        for &b in blocks.get(&addr).into_iter().flatten() {
+            let holders = with_blocks.entry(b).or_default();
+            if only_uncovered && !holders.is_empty() {
+                continue;
+            }
+            if holders.len() < max_per_block {
+                holders.insert(addr);
+            }
+        }
+    };
+    for &addr in chosen {
+        add(addr, false);
+    }
+    if with_file.is_empty() {
+        for &addr in &ranked[chosen.len()..] {
+            add(addr, true);
+        }
+    }
+    (with_file, with_blocks)
+}
+
+/// Fração dos bits iniciais que os dois endereços têm em comum.
+fn proximity(a: IpAddr, b: IpAddr) -> f64 {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => {
+            (a.to_bits() ^ b.to_bits()).leading_zeros() as f64 / 32.0
+        }
+        (IpAddr::V6(a), IpAddr::V6(b)) => {
+            (a.to_bits() ^ b.to_bits()).leading_zeros() as f64 / 128.0
+        }
+        _ => 0.0,
+    }
+}
+
+// xorshift64*, para desempatar peers equivalentes
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |d| d.as_nanos() as u64);
+        Rng(seed | 1)
+    }
+
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let x = self.0.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
@@ -0,0 +1,237 @@
+//! Limites de largura de banda com token buckets, um global e um por peer.
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Maior pedaço lido ou escrito de uma vez por um `Throttled`, para o
+/// tráfego sair espaçado em vez de em rajadas do tamanho de um bloco.
+const CHUNK: usize = 16 * 1024;
+/// Tempo sem tráfego ao fim do qual o bucket de um peer é esquecido.
+const IDLE_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Bucket {
+    // bytes/s, 0 = sem limite
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Bucket {
+            rate,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Reserva `n` tokens e devolve quanto tempo é preciso esperar por
+    /// eles. Os tokens podem ficar negativos: quem vem a seguir espera
+    /// também por essa dívida.
+    fn reserve(&mut self, n: usize) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        let rate = self.rate as f64;
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+
+    fn set_rate(&mut self, rate: u64) {
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate as f64);
+    }
+
+    /// Sem uso há `IDLE_AFTER` e já cheio outra vez: igual a um novo.
+    fn is_idle(&self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last);
+        let refill = elapsed.as_secs_f64() * self.rate as f64;
+        elapsed >= IDLE_AFTER && self.tokens + refill >= self.rate as f64
+    }
+}
+
+#[derive(Debug)]
+struct PerPeer {
+    rate: u64,
+    buckets: HashMap<IpAddr, Bucket>,
+    pruned: Instant,
+}
+
+impl PerPeer {
+    // Esquece os peers parados; corre no máximo uma vez por `IDLE_AFTER`
+    fn prune(&mut self) {
+        let now = Instant::now();
+        if now.duration_since(self.pruned) < IDLE_AFTER {
+            return;
+        }
+        self.pruned = now;
+        self.buckets.retain(|_, bucket| !bucket.is_idle(now));
+    }
+}
+
+/// Limite para um sentido do tráfego (upload ou download).
+#[derive(Debug)]
+pub struct RateLimiter {
+    global: Mutex<Bucket>,
+    per_peer: Mutex<PerPeer>,
+}
+
+impl RateLimiter {
+    /// Taxas em bytes/s; 0 desliga o limite.
+    pub fn new(global_rate: u64, per_peer_rate: u64) -> Self {
+        RateLimiter {
+            global: Mutex::new(Bucket::new(global_rate)),
+            per_peer: Mutex::new(PerPeer {
+                rate: per_peer_rate,
+                buckets: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// (global, por peer) em bytes/s.
+    pub fn rates(&self) -> (u64, u64) {
+        let global = self.global.lock().unwrap().rate;
+        let per_peer = self.per_peer.lock().unwrap().rate;
+        (global, per_peer)
+    }
+
+    pub fn set_global(&self, rate: u64) {
+        self.global.lock().unwrap().set_rate(rate);
+    }
+
+    pub fn set_per_peer(&self, rate: u64) {
+        let mut per_peer = self.per_peer.lock().unwrap();
+        per_peer.rate = rate;
+        for bucket in per_peer.buckets.values_mut() {
+            bucket.set_rate(rate);
+        }
+    }
+
+    /// Bloqueia até `n` bytes poderem passar de/para `peer`.
+    pub fn consume(&self, peer: IpAddr, n: usize) {
+        let global_wait = self.global.lock().unwrap().reserve(n);
+        let peer_wait = {
+            let mut per_peer = self.per_peer.lock().unwrap();
+            per_peer.prune();
+            let rate = per_peer.rate;
+            per_peer
+                .buckets
+                .entry(peer)
+                .or_insert_with(|| Bucket::new(rate))
+                .reserve(n)
+        };
+        let wait = global_wait.max(peer_wait);
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Stream cujo tráfego passa pelo `RateLimiter`.
+pub struct Throttled<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+    peer: IpAddr,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, limiter: Arc<RateLimiter>, peer: IpAddr) -> Self {
+        Throttled {
+            inner,
+            limiter,
+            peer,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: Read> Read for Throttled<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(CHUNK);
+        let n = self.inner.read(&mut buf[..len])?;
+        self.limiter.consume(self.peer, n);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Throttled<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(CHUNK);
+        self.limiter.consume(self.peer, len);
+        self.inner.write(&buf[..len])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ago(secs: u64) -> Instant {
+        Instant::now() - Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_waits_for_the_refill() {
+        let mut bucket = Bucket::new(1000);
+        assert_eq!(bucket.reserve(1000), Duration::ZERO);
+        // Dívida de 500 bytes a 1000 bytes/s
+        let wait = bucket.reserve(500);
+        assert!(wait > Duration::from_millis(450), "{:?}", wait);
+        assert!(wait <= Duration::from_millis(500), "{:?}", wait);
+
+        // Parado muito tempo, não acumula mais do que um segundo
+        bucket.last = ago(10);
+        assert_eq!(bucket.reserve(1000), Duration::ZERO);
+        assert!(bucket.reserve(100) > Duration::ZERO);
+    }
+
+    #[test]
+    fn zero_rate_never_waits() {
+        let mut bucket = Bucket::new(0);
+        assert_eq!(bucket.reserve(usize::MAX / 2), Duration::ZERO);
+        bucket.set_rate(10);
+        assert!(bucket.tokens <= 10.0);
+    }
+
+    #[test]
+    fn idle_peers_are_forgotten() {
+        let limiter = RateLimiter::new(0, 1_000_000);
+        let (a, b) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        limiter.consume(a, 10);
+        limiter.consume(b, 10);
+        {
+            let mut per_peer = limiter.per_peer.lock().unwrap();
+            per_peer.buckets.get_mut(&a).unwrap().last = ago(120);
+            // Ainda a pagar uma dívida: não pode ser esquecido
+            let busy = per_peer.buckets.get_mut(&b).unwrap();
+            busy.last = ago(120);
+            busy.tokens = -1e9;
+            per_peer.pruned = ago(120);
+        }
+        limiter.consume([10, 0, 0, 3].into(), 10);
+        let per_peer = limiter.per_peer.lock().unwrap();
+        assert!(!per_peer.buckets.contains_key(&a));
+        assert!(per_peer.buckets.contains_key(&b));
+        assert_eq!(per_peer.buckets.len(), 2);
+    }
+}
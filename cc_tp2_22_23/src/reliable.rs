@@ -0,0 +1,753 @@
+//! Entrega fiável de mensagens sobre UDP.
+//!
+//! Cada mensagem é partida em fragmentos numerados. O recetor confirma
+//! com ACKs cumulativos (o próximo fragmento que espera) e o emissor
+//! mantém uma janela deslizante de fragmentos por confirmar. A janela
+//! de cada peer é ainda limitada pelo controlo de congestão.
+//!
+//! Cada ACK leva também um bitmap (NACK) dos fragmentos em falta entre
+//! `cum` e o último recebido, e o emissor retransmite só esses (selective
+//! repeat). Um fragmento em falta só é dado como perdido se já chegou um
+//! fragmento enviado depois dele; o do início da janela é ainda
+//! retransmitido quando o seu timer expira (RTO calculado a partir do RTT
+//! medido, como no TCP).
+//!
+//! Pacotes:
+//! - DATA: `[1][msg_id u32][seq u32][total u32][dados]`
+//! - ACK: `[2][msg_id u32][cum u32][bits u16][bitmap]`, em que o bit `i`
+//!   do bitmap a 1 indica que falta o fragmento `cum + i`
+use crate::congestion::{Algorithm, CongestionControl};
+use crate::file_meta::bitmap_len;
+use bitvec::prelude::*;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DATA: u8 = 1;
+const ACK: u8 = 2;
+const DATA_HEADER: usize = 13;
+const ACK_HEADER: usize = 11;
+/// Fragmentos cobertos, no máximo, pelo bitmap de um ACK.
+const MAX_NACK_BITS: u32 = 1024;
+/// O último ACK de uma mensagem vai repetido, porque se se perder o
+/// emissor só desiste depois de esgotar as retransmissões.
+const FINAL_ACK_COPIES: usize = 3;
+/// Mensagens já entregues de que o recetor se lembra, para voltar a
+/// confirmar fragmentos duplicados.
+const DONE_MEMORY: usize = 1024;
+/// Mensagens incompletas sem fragmentos novos há mais do que isto são
+/// descartadas.
+const STALE_AFTER: Duration = Duration::from_secs(30);
+/// Limite de fragmentos por mensagem. Os fragmentos só ocupam memória
+/// quando chegam, mas o `total` anunciado tem de ser razoável.
+const MAX_FRAGMENTS: u32 = 1 << 16;
+/// Mensagens incompletas de um mesmo peer ao mesmo tempo; os fragmentos
+/// de mensagens novas para além disto são ignorados (e retransmitidos
+/// pelo emissor mais tarde).
+const MAX_INCOMING_PER_PEER: usize = 256;
+/// Estado de um peer (RTT, janela) sem envios há mais do que isto é
+/// esquecido.
+const LINK_IDLE_AFTER: Duration = Duration::from_secs(120);
+/// De quanto em quanto tempo a thread de receção verifica se o socket
+/// foi fechado.
+const POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Bytes de dados por datagrama.
+    pub fragment_size: usize,
+    /// Máximo de fragmentos por confirmar em simultâneo; a janela de
+    /// congestão nunca passa disto.
+    pub window: u32,
+    pub congestion: Algorithm,
+    pub initial_rto: Duration,
+    pub min_rto: Duration,
+    pub max_rto: Duration,
+    /// Timeouts seguidos, sem progresso, até o envio falhar.
+    pub max_retries: u32,
+    /// Probabilidade de descartar cada datagrama enviado, para testar
+    /// o protocolo sobre ligações com perdas.
+    pub loss_rate: f64,
+    /// Maior mensagem aceite de um peer, em bytes; as maiores são
+    /// ignoradas.
+    pub max_message: usize,
+    /// Bytes de mensagens incompletas guardados, somando todos os peers.
+    /// Os fragmentos que não cabem são ignorados (e retransmitidos pelo
+    /// emissor mais tarde).
+    pub max_buffered: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            fragment_size: 1200,
+            window: 64,
+            congestion: Algorithm::default(),
+            initial_rto: Duration::from_millis(500),
+            min_rto: Duration::from_millis(100),
+            max_rto: Duration::from_secs(4),
+            max_retries: 8,
+            loss_rate: 0.0,
+            max_message: 16 << 20,
+            max_buffered: 64 << 20,
+        }
+    }
+}
+
+/// Estimativa do RTT e do RTO (RFC 6298).
+#[derive(Debug, Clone, Copy)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    max_rto: Duration,
+    // Duplicações do RTO por timeouts seguidos
+    backoff: u32,
+}
+
+impl RttEstimator {
+    pub fn new(config: &Config) -> Self {
+        RttEstimator {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: config.initial_rto,
+            max_rto: config.max_rto,
+            backoff: 0,
+        }
+    }
+
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn rto(&self) -> Duration {
+        (self.rto * (1 << self.backoff)).min(self.max_rto)
+    }
+
+    pub fn sample(&mut self, rtt: Duration, config: &Config) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let diff = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + diff) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        let rto = self.srtt.unwrap() + self.rttvar * 4;
+        self.rto = rto.clamp(config.min_rto, config.max_rto);
+    }
+
+    pub fn backoff(&mut self) {
+        if self.rto() < self.max_rto {
+            self.backoff += 1;
+        }
+    }
+
+    /// Volta ao RTO normal quando há progresso.
+    pub fn reset_backoff(&mut self) {
+        self.backoff = 0;
+    }
+}
+
+/// Contadores de envio para um peer.
+#[derive(Debug, Clone, Default)]
+pub struct LinkStats {
+    pub srtt: Option<Duration>,
+    pub rto: Duration,
+    /// Janela de congestão atual, em fragmentos.
+    pub cwnd: u32,
+    /// Fragmentos enviados, incluindo retransmissões.
+    pub sent: u64,
+    pub retransmitted: u64,
+    pub timeouts: u64,
+}
+
+impl LinkStats {
+    /// Fração dos fragmentos enviados que teve de ser retransmitida.
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            0.0
+        } else {
+            self.retransmitted as f64 / self.sent as f64
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Link {
+    rtt: RttEstimator,
+    // Partilhado pelos envios em curso para o mesmo peer
+    cc: Box<dyn CongestionControl>,
+    stats: LinkStats,
+    last: Instant,
+}
+
+struct Shared {
+    socket: UdpSocket,
+    config: Config,
+    closed: AtomicBool,
+    rng: Mutex<u64>,
+    // Onde entregar os ACKs das mensagens a ser enviadas
+    acks: Mutex<HashMap<(SocketAddr, u32), Sender<Ack>>>,
+    links: Mutex<HashMap<SocketAddr, Link>>,
+}
+
+impl Shared {
+    fn send_raw(&self, packet: &[u8], peer: SocketAddr) -> io::Result<()> {
+        if self.config.loss_rate > 0.0 && self.random() < self.config.loss_rate
+        {
+            return Ok(());
+        }
+        self.socket.send_to(packet, peer)?;
+        Ok(())
+    }
+
+    fn send_ack(
+        &self,
+        msg_id: u32,
+        cum: u32,
+        nacks: &BitSlice<u8, Msb0>,
+        peer: SocketAddr,
+    ) {
+        let mut packet = Vec::with_capacity(ACK_HEADER + nacks.len() / 8 + 1);
+        packet.push(ACK);
+        packet.extend_from_slice(&msg_id.to_be_bytes());
+        packet.extend_from_slice(&cum.to_be_bytes());
+        packet.extend_from_slice(&(nacks.len() as u16).to_be_bytes());
+        let mut bitmap = vec![0u8; bitmap_len(nacks.len() as u32)];
+        bitmap.view_bits_mut::<Msb0>()[..nacks.len()].copy_from_bitslice(nacks);
+        packet.extend_from_slice(&bitmap);
+        // Um ACK perdido é recuperado pela retransmissão do emissor
+        let _ = self.send_raw(&packet, peer);
+    }
+
+    fn congestion<T>(
+        &self,
+        peer: SocketAddr,
+        f: impl FnOnce(&mut dyn CongestionControl) -> T,
+    ) -> T {
+        let mut links = self.links.lock().unwrap();
+        let link = links.entry(peer).or_insert_with(|| self.new_link());
+        link.last = Instant::now();
+        f(link.cc.as_mut())
+    }
+
+    fn new_link(&self) -> Link {
+        Link {
+            rtt: RttEstimator::new(&self.config),
+            cc: self.config.congestion.build(self.config.window),
+            stats: LinkStats::default(),
+            last: Instant::now(),
+        }
+    }
+
+    // xorshift64*, só para as perdas simuladas
+    fn random(&self) -> f64 {
+        let mut state = self.rng.lock().unwrap();
+        *state ^= *state >> 12;
+        *state ^= *state << 25;
+        *state ^= *state >> 27;
+        let x = state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Confirmação recebida para uma mensagem a ser enviada.
+struct Ack {
+    cum: u32,
+    nacks: BitVec<u8, Msb0>,
+}
+
+/// Mensagem a ser recebida.
+struct Incoming {
+    // Só os fragmentos que já chegaram, por ordem
+    frags: BTreeMap<u32, Vec<u8>>,
+    // Soma dos fragmentos guardados
+    bytes: usize,
+    total: u32,
+    // Próximo fragmento em falta
+    cum: u32,
+    // Maior fragmento recebido
+    highest: u32,
+    last: Instant,
+}
+
+impl Incoming {
+    fn new(total: u32) -> Self {
+        Incoming {
+            frags: BTreeMap::new(),
+            bytes: 0,
+            total,
+            cum: 0,
+            highest: 0,
+            last: Instant::now(),
+        }
+    }
+
+    // Devolve os bytes que passaram a estar guardados
+    fn insert(&mut self, seq: u32, payload: &[u8]) -> usize {
+        self.last = Instant::now();
+        self.highest = self.highest.max(seq);
+        if self.frags.contains_key(&seq) {
+            return 0;
+        }
+        self.frags.insert(seq, payload.to_vec());
+        self.bytes += payload.len();
+        while self.frags.contains_key(&self.cum) {
+            self.cum += 1;
+        }
+        payload.len()
+    }
+
+    /// Fragmentos em falta de `cum` até ao maior recebido.
+    fn nacks(&self) -> BitVec<u8, Msb0> {
+        let end = (self.highest + 1).min(self.cum + MAX_NACK_BITS);
+        (self.cum..end.max(self.cum))
+            .map(|seq| !self.frags.contains_key(&seq))
+            .collect()
+    }
+
+    fn into_data(self) -> Vec<u8> {
+        self.frags.into_values().flatten().collect()
+    }
+}
+
+/// Mensagem a ser enviada.
+struct Outgoing<'a> {
+    chunks: &'a [&'a [u8]],
+    msg_id: u32,
+    sent_at: Vec<Option<Instant>>,
+    retransmitted: Vec<bool>,
+    // Recebidos fora de ordem, segundo os bitmaps dos ACKs
+    sacked: Vec<bool>,
+}
+
+impl Outgoing<'_> {
+    fn send(
+        &mut self,
+        seq: u32,
+        shared: &Shared,
+        peer: SocketAddr,
+        stats: &mut LinkStats,
+    ) -> io::Result<()> {
+        let chunk = self.chunks[seq as usize];
+        let total = self.chunks.len() as u32;
+        let mut packet = Vec::with_capacity(DATA_HEADER + chunk.len());
+        packet.push(DATA);
+        packet.extend_from_slice(&self.msg_id.to_be_bytes());
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&total.to_be_bytes());
+        packet.extend_from_slice(chunk);
+        if self.sent_at[seq as usize].is_some() {
+            self.retransmitted[seq as usize] = true;
+            stats.retransmitted += 1;
+        }
+        self.sent_at[seq as usize] = Some(Instant::now());
+        stats.sent += 1;
+        shared.send_raw(&packet, peer)
+    }
+
+    /// Regista os fragmentos que o `ack` dá como recebidos e devolve os
+    /// que estão perdidos: em falta, apesar de o último fragmento coberto
+    /// pelo bitmap (que chegou) ter sido enviado depois deles.
+    fn lost(&mut self, ack: &Ack) -> Vec<u32> {
+        let Some(last) = ack.nacks.len().checked_sub(1) else {
+            return Vec::new();
+        };
+        let newest = self.sent_at[ack.cum as usize + last];
+        let mut lost = Vec::new();
+        for (i, missing) in ack.nacks.iter().by_vals().enumerate() {
+            let seq = ack.cum as usize + i;
+            if !missing {
+                self.sacked[seq] = true;
+            } else if !self.sacked[seq] && self.sent_at[seq] < newest {
+                lost.push(seq as u32);
+            }
+        }
+        lost
+    }
+}
+
+/// Socket UDP que entrega mensagens completas e por ordem de fragmentos,
+/// com retransmissão das perdas.
+pub struct ReliableSocket {
+    shared: Arc<Shared>,
+    inbox: Mutex<Receiver<(Vec<u8>, SocketAddr)>>,
+    next_msg_id: AtomicU32,
+    receiver: Option<JoinHandle<()>>,
+}
+
+impl ReliableSocket {
+    pub fn bind<A: ToSocketAddrs>(addr: A, config: Config) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_read_timeout(Some(POLL))?;
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |d| d.as_nanos() as u64)
+            | 1;
+        let shared = Arc::new(Shared {
+            socket,
+            config,
+            closed: AtomicBool::new(false),
+            rng: Mutex::new(seed),
+            acks: Mutex::new(HashMap::new()),
+            links: Mutex::new(HashMap::new()),
+        });
+        let (tx, rx) = mpsc::channel();
+        let recv_shared = shared.clone();
+        let receiver = thread::spawn(move || receive_loop(&recv_shared, tx));
+        Ok(ReliableSocket {
+            shared,
+            inbox: Mutex::new(rx),
+            next_msg_id: AtomicU32::new(seed as u32),
+            receiver: Some(receiver),
+        })
+    }
+
+    /// Fecha o socket; as chamadas bloqueadas nele, noutras threads,
+    /// falham pouco depois.
+    pub fn close(&self) {
+        self.shared.closed.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Relaxed)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.socket.local_addr()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.shared.config
+    }
+
+    pub fn link_stats(&self) -> Vec<(SocketAddr, LinkStats)> {
+        let links = self.shared.links.lock().unwrap();
+        let stats = |l: &Link| LinkStats {
+            cwnd: l.cc.window(),
+            ..l.stats.clone()
+        };
+        links.iter().map(|(a, l)| (*a, stats(l))).collect()
+    }
+
+    /// Bloqueia até a próxima mensagem estar completa.
+    pub fn recv_from(&self) -> io::Result<(Vec<u8>, SocketAddr)> {
+        let inbox = self.inbox.lock().unwrap();
+        inbox.recv().map_err(|_| closed())
+    }
+
+    pub fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> io::Result<(Vec<u8>, SocketAddr)> {
+        let inbox = self.inbox.lock().unwrap();
+        match inbox.recv_timeout(timeout) {
+            Ok(msg) => Ok(msg),
+            Err(RecvTimeoutError::Timeout) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no message received",
+            )),
+            Err(RecvTimeoutError::Disconnected) => Err(closed()),
+        }
+    }
+
+    /// Envia `data` para `peer` e bloqueia até todos os fragmentos
+    /// estarem confirmados. Pode ser chamado de várias threads.
+    pub fn send_to(&self, data: &[u8], peer: SocketAddr) -> io::Result<()> {
+        self.send(data, peer, None)
+    }
+
+    /// Como `send_to`, mas desiste ao fim de `timeout` mesmo que ainda
+    /// houvesse retransmissões por fazer.
+    pub fn send_timeout(
+        &self,
+        data: &[u8],
+        peer: SocketAddr,
+        timeout: Duration,
+    ) -> io::Result<()> {
+        self.send(data, peer, Some(Instant::now() + timeout))
+    }
+
+    fn send(
+        &self,
+        data: &[u8],
+        peer: SocketAddr,
+        give_up: Option<Instant>,
+    ) -> io::Result<()> {
+        let chunks: Vec<&[u8]> = if data.is_empty() {
+            vec![&[]]
+        } else {
+            data.chunks(self.shared.config.fragment_size).collect()
+        };
+        if chunks.len() > MAX_FRAGMENTS as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "message too large",
+            ));
+        }
+        let msg_id = self.next_msg_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel();
+        self.shared.acks.lock().unwrap().insert((peer, msg_id), tx);
+        let res = self.send_fragments(&chunks, msg_id, peer, &rx, give_up);
+        self.shared.acks.lock().unwrap().remove(&(peer, msg_id));
+        res
+    }
+
+    fn send_fragments(
+        &self,
+        chunks: &[&[u8]],
+        msg_id: u32,
+        peer: SocketAddr,
+        acks: &Receiver<Ack>,
+        give_up: Option<Instant>,
+    ) -> io::Result<()> {
+        let config = &self.shared.config;
+        let total = chunks.len() as u32;
+        let mut rtt = {
+            let mut links = self.shared.links.lock().unwrap();
+            links.retain(|_, l| l.last.elapsed() < LINK_IDLE_AFTER);
+            let link =
+                links.entry(peer).or_insert_with(|| self.shared.new_link());
+            link.last = Instant::now();
+            link.rtt
+        };
+        // Só os contadores deste envio; são somados no fim
+        let mut stats = LinkStats::default();
+
+        let mut out = Outgoing {
+            chunks,
+            msg_id,
+            sent_at: vec![None; total as usize],
+            retransmitted: vec![false; total as usize],
+            sacked: vec![false; total as usize],
+        };
+        let mut base = 0;
+        let mut next = 0;
+        // Fragmento até ao qual as perdas contam como uma só para o
+        // controlo de congestão
+        let mut recovery = 0;
+        let mut retries = 0;
+        let res = 'send: loop {
+            if self.shared.closed.load(Ordering::Relaxed) {
+                break Err(closed());
+            }
+            let window = self.shared.congestion(peer, |cc| cc.window());
+            while next < total && next < base + window {
+                if let Err(e) = out.send(next, &self.shared, peer, &mut stats) {
+                    break 'send Err(e);
+                }
+                next += 1;
+            }
+            if base == total {
+                break Ok(());
+            }
+
+            let mut deadline = out.sent_at[base as usize].unwrap() + rtt.rto();
+            if let Some(give_up) = give_up {
+                deadline = deadline.min(give_up);
+            }
+            let wait = deadline.saturating_duration_since(Instant::now());
+            let ack = match acks.recv_timeout(wait) {
+                // Um ACK à frente do que foi enviado é velho ou falso
+                Ok(ack) if ack.cum >= base && ack.cum <= next => ack,
+                Ok(_) => continue,
+                Err(RecvTimeoutError::Timeout) => {
+                    retries += 1;
+                    stats.timeouts += 1;
+                    let expired = give_up.is_some_and(|t| Instant::now() >= t);
+                    if retries > config.max_retries || expired {
+                        break Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("{} stopped acknowledging", peer),
+                        ));
+                    }
+                    rtt.backoff();
+                    self.shared.congestion(peer, |cc| cc.on_timeout());
+                    recovery = next;
+                    let sent = out.send(base, &self.shared, peer, &mut stats);
+                    if let Err(e) = sent {
+                        break Err(e);
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break Err(closed()),
+            };
+            // Um bitmap que vai além do que foi enviado é inválido
+            if ack.cum as usize + ack.nacks.len() > next as usize {
+                continue;
+            }
+            let cum = ack.cum;
+            if cum > base {
+                // Algoritmo de Karn: só se mede o RTT se nenhum dos
+                // fragmentos confirmados foi retransmitido; senão o ACK
+                // pode ser de outra cópia, ou ter esperado por um buraco
+                let range = base as usize..cum as usize;
+                let sent_at = out.sent_at[(cum - 1) as usize];
+                if let (false, Some(sent_at)) =
+                    (out.retransmitted[range].contains(&true), sent_at)
+                {
+                    rtt.sample(sent_at.elapsed(), config);
+                }
+                rtt.reset_backoff();
+                self.shared.congestion(peer, |cc| cc.on_ack(cum - base));
+                base = cum;
+                retries = 0;
+            }
+
+            let lost = out.lost(&ack);
+            if !lost.is_empty() && base >= recovery {
+                self.shared.congestion(peer, |cc| cc.on_loss());
+                recovery = next;
+            }
+            for seq in lost {
+                if let Err(e) = out.send(seq, &self.shared, peer, &mut stats) {
+                    break 'send Err(e);
+                }
+            }
+        };
+
+        let mut links = self.shared.links.lock().unwrap();
+        if let Some(link) = links.get_mut(&peer) {
+            link.rtt = rtt;
+            link.stats.srtt = rtt.srtt();
+            link.stats.rto = rtt.rto();
+            link.stats.sent += stats.sent;
+            link.stats.retransmitted += stats.retransmitted;
+            link.stats.timeouts += stats.timeouts;
+        }
+        res
+    }
+}
+
+impl Drop for ReliableSocket {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Relaxed);
+        if let Some(receiver) = self.receiver.take() {
+            let _ = receiver.join();
+        }
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "socket closed")
+}
+
+fn receive_loop(shared: &Shared, inbox: Sender<(Vec<u8>, SocketAddr)>) {
+    let mut incoming: HashMap<(SocketAddr, u32), Incoming> = HashMap::new();
+    let mut done: VecDeque<(SocketAddr, u32, u32)> = VecDeque::new();
+    let mut buf = vec![0u8; 65536];
+    let mut purged = Instant::now();
+    // Bytes guardados em `incoming`
+    let mut buffered = 0;
+
+    while !shared.closed.load(Ordering::Relaxed) {
+        // Também com tráfego constante, que nunca chega ao timeout
+        if purged.elapsed() >= POLL {
+            incoming.retain(|_, m| {
+                let keep = m.last.elapsed() < STALE_AFTER;
+                if !keep {
+                    buffered -= m.bytes;
+                }
+                keep
+            });
+            purged = Instant::now();
+        }
+        let (n, peer) = match shared.socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            // Timeout do POLL, ou um ICMP de um envio anterior
+            Err(_) => continue,
+        };
+        let packet = &buf[..n];
+        match packet.first().copied().unwrap_or(0) {
+            ACK if n >= ACK_HEADER => {
+                let msg_id =
+                    u32::from_be_bytes(packet[1..5].try_into().unwrap());
+                let cum = u32::from_be_bytes(packet[5..9].try_into().unwrap());
+                let bits =
+                    u16::from_be_bytes(packet[9..11].try_into().unwrap());
+                let Some(bitmap) = packet
+                    .get(ACK_HEADER..ACK_HEADER + bitmap_len(bits as u32))
+                else {
+                    continue;
+                };
+                let mut nacks = BitVec::<u8, Msb0>::from_slice(bitmap);
+                nacks.truncate(bits as usize);
+                let acks = shared.acks.lock().unwrap();
+                if let Some(tx) = acks.get(&(peer, msg_id)) {
+                    let _ = tx.send(Ack { cum, nacks });
+                }
+            }
+            DATA if n >= DATA_HEADER => {
+                let msg_id =
+                    u32::from_be_bytes(packet[1..5].try_into().unwrap());
+                let seq = u32::from_be_bytes(packet[5..9].try_into().unwrap());
+                let total =
+                    u32::from_be_bytes(packet[9..13].try_into().unwrap());
+                let payload = &packet[DATA_HEADER..];
+                if seq >= total || total > MAX_FRAGMENTS {
+                    continue;
+                }
+                // Todos os fragmentos menos o último têm o mesmo tamanho
+                let declared = if seq + 1 < total {
+                    total as usize * payload.len()
+                } else {
+                    0
+                };
+                if declared > shared.config.max_message {
+                    continue;
+                }
+                if let Some((.., total)) =
+                    done.iter().find(|(p, id, _)| *p == peer && *id == msg_id)
+                {
+                    shared.send_ack(msg_id, *total, BitSlice::empty(), peer);
+                    continue;
+                }
+                if !incoming.contains_key(&(peer, msg_id))
+                    && incoming.keys().filter(|(p, _)| *p == peer).count()
+                        >= MAX_INCOMING_PER_PEER
+                {
+                    continue;
+                }
+                let (known, bytes) = incoming
+                    .get(&(peer, msg_id))
+                    .map_or((total, 0), |m| (m.total, m.bytes));
+                if known != total
+                    || bytes + payload.len() > shared.config.max_message
+                    || buffered + payload.len() > shared.config.max_buffered
+                {
+                    continue;
+                }
+                let msg = incoming
+                    .entry((peer, msg_id))
+                    .or_insert_with(|| Incoming::new(total));
+                buffered += msg.insert(seq, payload);
+                if msg.cum < total {
+                    shared.send_ack(msg_id, msg.cum, &msg.nacks(), peer);
+                    continue;
+                }
+                let msg = incoming.remove(&(peer, msg_id)).unwrap();
+                buffered -= msg.bytes;
+                for _ in 0..FINAL_ACK_COPIES {
+                    shared.send_ack(msg_id, total, BitSlice::empty(), peer);
+                }
+                done.push_back((peer, msg_id, total));
+                if done.len() > DONE_MEMORY {
+                    done.pop_front();
+                }
+                if inbox.send((msg.into_data(), peer)).is_err() {
+                    return;
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,730 @@
+use crate::config::SharedRoot;
+use crate::file_meta::{n_blocks, FileMeta, BLOCK_SIZE};
+use anyhow::{bail, Context};
+use bitvec::prelude::*;
+use sha1::{Digest as _, Sha1};
+use std::collections::HashMap;
+use std::fs::{self, read_dir, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+/// SHA-1 de um bloco.
+pub type Digest = [u8; 20];
+
+/// Extensão dos dados de um download incompleto.
+pub const PART_EXT: &str = "part";
+/// Extensão do ficheiro de estado que acompanha cada `.part`.
+pub const STATE_EXT: &str = "state";
+
+pub fn digest(data: &[u8]) -> Digest {
+    Sha1::digest(data).into()
+}
+
+/// Nome com que um ficheiro é anunciado: o caminho relativo ao
+/// diretório partilhado, com `/` entre os componentes (`docs/a.pdf`).
+/// Aceita também `\` e componentes `.`; devolve `None` se o caminho
+/// for absoluto ou puder sair do diretório.
+pub fn normalize_name(name: &str) -> Option<String> {
+    if name.starts_with(['/', '\\']) || name.contains('\0') {
+        return None;
+    }
+    let parts: Vec<&str> = name
+        .split(['/', '\\'])
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect();
+    if parts.is_empty() || parts.contains(&"..") {
+        return None;
+    }
+    Some(parts.join("/"))
+}
+
+/// Se `name` já está na forma normalizada, e pode ser usado como
+/// caminho dentro do diretório partilhado.
+pub fn is_valid_name(name: &str) -> bool {
+    normalize_name(name).is_some_and(|n| n == name)
+}
+
+pub fn part_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.{}", name, PART_EXT))
+}
+
+pub fn state_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.{}", name, STATE_EXT))
+}
+
+/// Informação necessária para descarregar e verificar um ficheiro.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileInfo {
+    pub f_size: u64,
+    pub block_size: u32,
+    pub digests: Vec<Digest>,
+}
+
+impl FileInfo {
+    pub fn n_blocks(&self) -> u32 {
+        n_blocks(self.f_size, self.block_size)
+    }
+
+    pub fn block_offset(&self, block: u32) -> u64 {
+        block as u64 * self.block_size as u64
+    }
+
+    pub fn block_len(&self, block: u32) -> usize {
+        let remaining = self.f_size - self.block_offset(block);
+        remaining.min(self.block_size as u64) as usize
+    }
+
+    fn compute(
+        path: &Path,
+        f_size: u64,
+        block_size: u32,
+    ) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        let mut info = FileInfo {
+            f_size,
+            block_size,
+            digests: Vec::new(),
+        };
+        let mut buf = vec![0u8; block_size as usize];
+        for block in 0..info.n_blocks() {
+            let data = &mut buf[..info.block_len(block)];
+            file.read_exact_at(data, info.block_offset(block))?;
+            info.digests.push(digest(data));
+        }
+        Ok(info)
+    }
+}
+
+/// Estado persistido ao lado de um download incompleto (`<nome>.state`).
+#[derive(Debug, Clone)]
+pub struct PartialState {
+    pub name: String,
+    pub info: FileInfo,
+    pub blocks: BitVec<u8, Msb0>,
+}
+
+impl PartialState {
+    pub fn new(name: String, info: FileInfo) -> Self {
+        let n_blocks = info.n_blocks() as usize;
+        PartialState {
+            name,
+            info,
+            blocks: bitvec![u8, Msb0; 0; n_blocks],
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.blocks.all()
+    }
+
+    pub fn missing(&self) -> Vec<u32> {
+        self.blocks.iter_zeros().map(|b| b as u32).collect()
+    }
+
+    pub fn meta(&self) -> FileMeta {
+        FileMeta {
+            f_size: self.info.f_size,
+            has_full_file: false,
+            block_size: self.info.block_size,
+            blocks_len: self.info.n_blocks(),
+            name_len: self.name.len() as u16,
+            blocks: self.blocks.clone(),
+            name: self.name.clone(),
+        }
+    }
+
+    /// Formato de texto, uma chave por linha:
+    /// `name`, `size`, `block_size`, `blocks` (0/1 por bloco) e um
+    /// `digest` em hex por bloco, por ordem.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut out = String::new();
+        out.push_str(&format!("name {}\n", self.name));
+        out.push_str(&format!("size {}\n", self.info.f_size));
+        out.push_str(&format!("block_size {}\n", self.info.block_size));
+        let bits: String = self
+            .blocks
+            .iter()
+            .map(|b| if *b { '1' } else { '0' })
+            .collect();
+        out.push_str(&format!("blocks {}\n", bits));
+        for d in &self.info.digests {
+            out.push_str(&format!("digest {}\n", to_hex(d)));
+        }
+        // Escreve para um temporário e renomeia para nunca deixar o
+        // estado a meio se o nó parar durante a escrita
+        let tmp = path.with_extension(format!("{}.tmp", STATE_EXT));
+        fs::write(&tmp, out)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let raw = fs::read_to_string(path)?;
+        let mut name = None;
+        let mut f_size = None;
+        let mut block_size = None;
+        let mut bits = None;
+        let mut digests = Vec::new();
+        for line in raw.lines() {
+            let (key, val) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "name" => name = Some(val.to_string()),
+                "size" => f_size = Some(val.parse::<u64>()?),
+                "block_size" => block_size = Some(val.parse::<u32>()?),
+                "blocks" => bits = Some(val.to_string()),
+                "digest" => digests.push(from_hex(val)?),
+                _ => bail!("Unknown key in state file: {}", key),
+            }
+        }
+        let (Some(name), Some(f_size), Some(block_size), Some(bits)) =
+            (name, f_size, block_size, bits)
+        else {
+            bail!("Incomplete state file: {}", path.display());
+        };
+        // Com o limite do tamanho do bloco, o `.part` que `verify` cria
+        // nunca passa de `BLOCK_SIZE` por digest no estado
+        if block_size == 0 || block_size > BLOCK_SIZE {
+            bail!("Invalid block size in {}", path.display());
+        }
+        if !is_valid_name(&name) {
+            bail!("Invalid file name in {}", path.display());
+        }
+        let info = FileInfo {
+            f_size,
+            block_size,
+            digests,
+        };
+        let n_blocks = info.n_blocks() as usize;
+        if info.digests.len() != n_blocks || bits.len() != n_blocks {
+            bail!("Block count mismatch in {}", path.display());
+        }
+        let blocks = bits.chars().map(|c| c == '1').collect();
+        Ok(PartialState { name, info, blocks })
+    }
+
+    /// Volta a verificar todos os blocos do `.part` contra os digests.
+    /// Blocos escritos antes de o estado ter sido guardado também contam.
+    pub fn verify(&mut self, part: &Path) -> anyhow::Result<()> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(part)?;
+        if file.metadata()?.len() != self.info.f_size {
+            file.set_len(self.info.f_size)?;
+        }
+        let mut buf = vec![0u8; self.info.block_size as usize];
+        for block in 0..self.info.n_blocks() {
+            let data = &mut buf[..self.info.block_len(block)];
+            let ok = file
+                .read_exact_at(data, self.info.block_offset(block))
+                .is_ok()
+                && digest(data) == self.info.digests[block as usize];
+            self.blocks.set(block as usize, ok);
+        }
+        Ok(())
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> anyhow::Result<Digest> {
+    let mut d = [0u8; 20];
+    if s.len() != 40 {
+        bail!("Invalid digest: {}", s);
+    }
+    for (i, byte) in d.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16)?;
+    }
+    Ok(d)
+}
+
+// Ficheiros em `dir` e nos subdiretórios, com o nome relativo a `root`.
+// Os links para diretórios não são seguidos, para não haver ciclos
+fn walk(
+    root: &Path,
+    dir: &Path,
+    found: &mut Vec<(String, PathBuf)>,
+) -> anyhow::Result<()> {
+    let entries = read_dir(dir).with_context(|| {
+        format!("failed to read directory: {}", dir.display())
+    })?;
+    for try_entry in entries {
+        let entry = try_entry.context("failed to read entry")?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            walk(root, &path, found)?;
+            continue;
+        }
+        if !path.is_file() {
+            continue;
+        }
+        if let Some(name) = relative_name(root, &path) {
+            found.push((name, path));
+        }
+    }
+    Ok(())
+}
+
+// `None` se algum componente não for UTF-8
+fn relative_name(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts: Option<Vec<&str>> = relative
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect();
+    Some(parts?.join("/"))
+}
+
+#[derive(Debug)]
+struct LocalFile {
+    // Caminho dos dados (o `.part` enquanto o ficheiro estiver incompleto)
+    path: PathBuf,
+    meta: FileMeta,
+    // Digests dos blocos, calculados só quando um peer os pede
+    info: Option<FileInfo>,
+    // Raiz de onde vem (índice em `FileStore::roots`)
+    root: usize,
+}
+
+impl LocalFile {
+    fn full(name: String, path: PathBuf, root: usize) -> io::Result<Self> {
+        let f_size = fs::metadata(&path)?.len();
+        Ok(LocalFile {
+            path,
+            meta: FileMeta::full(name, f_size, BLOCK_SIZE),
+            info: None,
+            root,
+        })
+    }
+}
+
+/// Ficheiros que o nó tem (completos ou não) nas raízes partilhadas e
+/// nos seus subdiretórios, pelo nome anunciado: o caminho relativo (ver
+/// `normalize_name`), com o prefixo da raiz. Se duas raízes derem o
+/// mesmo nome, fica o ficheiro da primeira.
+#[derive(Debug)]
+pub struct FileStore {
+    roots: Vec<SharedRoot>,
+    files: RwLock<HashMap<String, LocalFile>>,
+}
+
+impl FileStore {
+    /// Só com um diretório, sem prefixo.
+    pub fn open(dir: &Path) -> anyhow::Result<Self> {
+        Self::open_roots(vec![SharedRoot::main(dir.to_path_buf())])
+    }
+
+    /// A primeira raiz é a principal: recebe os downloads que não cabem
+    /// numa das outras (ver `part_path`).
+    pub fn open_roots(roots: Vec<SharedRoot>) -> anyhow::Result<Self> {
+        if roots.first().is_none_or(|root| root.read_only) {
+            bail!("The first shared root must be writable");
+        }
+        // Um diretório da principal com o nome do prefixo de outra raiz
+        // dá os mesmos nomes: fica o ficheiro da principal
+        for root in &roots[1..] {
+            let Some(prefix) = &root.prefix else {
+                continue;
+            };
+            let dir = roots[0].path.join(prefix);
+            if dir.is_dir() {
+                println!(
+                    "Warning: {} clashes with the root {} (prefix {}); \
+                     the main root's files win",
+                    dir.display(),
+                    root.path.display(),
+                    prefix
+                );
+            }
+        }
+        let store = FileStore {
+            roots,
+            files: RwLock::new(HashMap::new()),
+        };
+        for root in 0..store.roots.len() {
+            store.load_root(root)?;
+        }
+        Ok(store)
+    }
+
+    // Os ficheiros completos da raiz e, se tiver escrita, os downloads
+    // incompletos, já reverificados
+    fn load_root(&self, root: usize) -> anyhow::Result<()> {
+        let mut files = self.files.write().unwrap();
+        let mut states = Vec::new();
+        for (name, path) in self.scan(root)? {
+            if self.is_sidecar(root, &path) {
+                if path.extension().is_some_and(|ext| ext == STATE_EXT) {
+                    states.push(path);
+                }
+                continue;
+            }
+            if files.contains_key(&name) {
+                println!(
+                    "Ignoring {}: {} is already shared",
+                    path.display(),
+                    name
+                );
+                continue;
+            }
+            let local = LocalFile::full(name.clone(), path, root)?;
+            files.insert(name, local);
+        }
+
+        for path in states {
+            let mut state = match PartialState::load(&path) {
+                Ok(state) => state,
+                Err(e) => {
+                    println!("Ignoring {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            if files.contains_key(&state.name) {
+                continue;
+            }
+            // Estado deixado antes de a raiz do nome ser configurada
+            if self.target(&state.name).0 != root {
+                println!(
+                    "Ignoring {}: downloads of {} go elsewhere",
+                    path.display(),
+                    state.name
+                );
+                continue;
+            }
+            let part = self.part_path(&state.name);
+            let verified = state.verify(&part).and_then(|_| state.save(&path));
+            if let Err(e) = verified {
+                println!("Ignoring {}: {}", path.display(), e);
+                continue;
+            }
+            let local = LocalFile {
+                path: part,
+                meta: state.meta(),
+                info: Some(state.info.clone()),
+                root,
+            };
+            files.insert(state.name.clone(), local);
+        }
+        Ok(())
+    }
+
+    // Todos os ficheiros da raiz, pelo nome anunciado
+    fn scan(&self, root: usize) -> anyhow::Result<Vec<(String, PathBuf)>> {
+        let path = &self.roots[root].path;
+        let mut found = Vec::new();
+        walk(path, path, &mut found)?;
+        // O temporário de `PartialState::save`, se o nó parou a meio
+        let tmp = format!(".{}.tmp", STATE_EXT);
+        if !self.roots[root].read_only {
+            found.retain(|(name, _)| !name.ends_with(&tmp));
+        }
+        for (name, _) in &mut found {
+            *name = self.announced_name(root, name);
+        }
+        Ok(found)
+    }
+
+    // Os `.part` e os `.state` dos downloads; numa raiz só de leitura o nó
+    // nunca os escreve, e são ficheiros como os outros
+    fn is_sidecar(&self, root: usize, path: &Path) -> bool {
+        let ext = path.extension().and_then(|s| s.to_str());
+        !self.roots[root].read_only && matches!(ext, Some(STATE_EXT | PART_EXT))
+    }
+
+    // `relative` é o caminho dentro da raiz
+    fn announced_name(&self, root: usize, relative: &str) -> String {
+        match &self.roots[root].prefix {
+            Some(prefix) => format!("{}/{}", prefix, relative),
+            None => relative.to_string(),
+        }
+    }
+
+    // Onde escrever o download de `name`: a raiz com escrita cujo
+    // prefixo o nome tem, com o resto do nome, ou a principal com o nome
+    // inteiro
+    fn target<'n>(&self, name: &'n str) -> (usize, &'n str) {
+        for (i, root) in self.roots.iter().enumerate() {
+            let Some(prefix) = &root.prefix else {
+                continue;
+            };
+            let rest = name
+                .strip_prefix(prefix.as_str())
+                .and_then(|rest| rest.strip_prefix('/'));
+            if let (Some(rest), false) = (rest, root.read_only) {
+                return (i, rest);
+            }
+        }
+        (0, name)
+    }
+
+    /// Diretório da raiz principal.
+    pub fn dir(&self) -> &Path {
+        &self.roots[0].path
+    }
+
+    pub fn roots(&self) -> &[SharedRoot] {
+        &self.roots
+    }
+
+    /// Índice da raiz com o prefixo `key`; `-` é a principal.
+    pub fn find_root(&self, key: &str) -> Option<usize> {
+        self.roots.iter().position(|root| match &root.prefix {
+            Some(prefix) => prefix == key,
+            None => key == "-",
+        })
+    }
+
+    /// Ficheiros partilhados a partir da raiz.
+    pub fn count(&self, root: usize) -> usize {
+        let files = self.files.read().unwrap();
+        files.values().filter(|f| f.root == root).count()
+    }
+
+    /// Volta a ler os ficheiros completos da raiz: devolve os que passaram
+    /// a ser partilhados (novos ou com outro tamanho) e os nomes dos que
+    /// desapareceram. Os downloads incompletos não mudam.
+    pub fn rescan(
+        &self,
+        root: usize,
+    ) -> anyhow::Result<(Vec<FileMeta>, Vec<String>)> {
+        let on_disk: HashMap<String, PathBuf> = self
+            .scan(root)?
+            .into_iter()
+            .filter(|(_, path)| !self.is_sidecar(root, path))
+            .collect();
+
+        // Tudo lido antes de mexer no índice, para um erro não o deixar
+        // a meio
+        let found = on_disk
+            .iter()
+            .map(|(name, path)| {
+                LocalFile::full(name.clone(), path.clone(), root)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut files = self.files.write().unwrap();
+        let mut removed = Vec::new();
+        files.retain(|name, f| {
+            let gone = f.root == root
+                && f.meta.has_full_file
+                && !on_disk.contains_key(name);
+            if gone {
+                removed.push(name.clone());
+            }
+            !gone
+        });
+        let mut added = Vec::new();
+        for local in found {
+            let name = local.meta.name.clone();
+            match files.get(&name) {
+                // Ainda a ser descarregado
+                Some(f) if !f.meta.has_full_file => continue,
+                // Como em `load_root`, fica o da raiz que vem primeiro
+                Some(f) if f.root < root => {
+                    println!(
+                        "Ignoring {}: {} is already shared",
+                        local.path.display(),
+                        name
+                    );
+                    continue;
+                }
+                Some(f) if f.root > root => {
+                    println!(
+                        "Ignoring {}: {} is already shared",
+                        f.path.display(),
+                        name
+                    );
+                }
+                Some(f)
+                    if f.path == local.path
+                        && f.meta.f_size == local.meta.f_size =>
+                {
+                    continue
+                }
+                _ => {}
+            }
+            added.push(local.meta.clone());
+            files.insert(name, local);
+        }
+        Ok((added, removed))
+    }
+
+    /// Onde fica o `.part` de um download de `name`.
+    pub fn part_path(&self, name: &str) -> PathBuf {
+        let (root, rest) = self.target(name);
+        part_path(&self.roots[root].path, rest)
+    }
+
+    /// Onde fica o estado de um download de `name`.
+    pub fn state_path(&self, name: &str) -> PathBuf {
+        let (root, rest) = self.target(name);
+        state_path(&self.roots[root].path, rest)
+    }
+
+    pub fn metas(&self) -> Vec<FileMeta> {
+        let files = self.files.read().unwrap();
+        files.values().map(|f| f.meta.clone()).collect()
+    }
+
+    pub fn meta(&self, name: &str) -> Option<FileMeta> {
+        let files = self.files.read().unwrap();
+        files.get(name).map(|f| f.meta.clone())
+    }
+
+    pub fn info(&self, name: &str) -> anyhow::Result<Option<FileInfo>> {
+        let (path, f_size, block_size) = {
+            let files = self.files.read().unwrap();
+            match files.get(name) {
+                None => return Ok(None),
+                Some(LocalFile {
+                    info: Some(info), ..
+                }) => return Ok(Some(info.clone())),
+                Some(f) => (f.path.clone(), f.meta.f_size, f.meta.block_size),
+            }
+        };
+        let info = FileInfo::compute(&path, f_size, block_size)?;
+        if let Some(f) = self.files.write().unwrap().get_mut(name) {
+            f.info = Some(info.clone());
+        }
+        Ok(Some(info))
+    }
+
+    /// Lê um bloco, se o nó o tiver.
+    pub fn read_block(
+        &self,
+        name: &str,
+        block: u32,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let (path, meta) = {
+            let files = self.files.read().unwrap();
+            match files.get(name) {
+                Some(f) => (f.path.clone(), f.meta.clone()),
+                None => return Ok(None),
+            }
+        };
+        if block >= meta.blocks_len || !meta.blocks[block as usize] {
+            return Ok(None);
+        }
+        let offset = block as u64 * meta.block_size as u64;
+        let len = (meta.f_size - offset).min(meta.block_size as u64);
+        let mut data = vec![0u8; len as usize];
+        File::open(path)?.read_exact_at(&mut data, offset)?;
+        Ok(Some(data))
+    }
+
+    /// Regista um download incompleto para os seus blocos poderem ser
+    /// partilhados.
+    pub fn add_partial(&self, state: &PartialState) {
+        let local = LocalFile {
+            path: self.part_path(&state.name),
+            meta: state.meta(),
+            info: Some(state.info.clone()),
+            root: self.target(&state.name).0,
+        };
+        let mut files = self.files.write().unwrap();
+        files.insert(state.name.clone(), local);
+    }
+
+    pub fn set_block(&self, name: &str, block: u32) {
+        let mut files = self.files.write().unwrap();
+        if let Some(f) = files.get_mut(name) {
+            f.meta.blocks.set(block as usize, true);
+        }
+    }
+
+    /// Partilha um ficheiro completo de uma das raízes, com o nome que
+    /// teria ao ler a raiz (sem ter de a reler toda).
+    pub fn share(&self, path: &Path) -> anyhow::Result<FileMeta> {
+        let metadata = fs::metadata(path)
+            .with_context(|| format!("Can't read {}", path.display()))?;
+        if !metadata.is_file() {
+            bail!("Not a file: {}", path.display());
+        }
+        let (root, relative) = self.locate(path)?;
+        if self.is_sidecar(root, path) {
+            bail!("Part of a download: {}", path.display());
+        }
+        let name = self.announced_name(root, &relative);
+        let mut files = self.files.write().unwrap();
+        if files.contains_key(&name) {
+            bail!("{} is already shared", name);
+        }
+        let meta = FileMeta::full(name.clone(), metadata.len(), BLOCK_SIZE);
+        let local = LocalFile {
+            path: self.roots[root].path.join(&relative),
+            meta: meta.clone(),
+            info: None,
+            root,
+        };
+        files.insert(name, local);
+        Ok(meta)
+    }
+
+    // A primeira raiz onde está `path`, e o caminho dentro dela
+    fn locate(&self, path: &Path) -> anyhow::Result<(usize, String)> {
+        let path = fs::canonicalize(path)?;
+        for (i, root) in self.roots.iter().enumerate() {
+            let Ok(dir) = fs::canonicalize(&root.path) else {
+                continue;
+            };
+            if !path.starts_with(&dir) {
+                continue;
+            }
+            match relative_name(&dir, &path) {
+                Some(relative) if is_valid_name(&relative) => {
+                    return Ok((i, relative))
+                }
+                _ => bail!("Invalid file name: {}", path.display()),
+            }
+        }
+        bail!("Not in a shared root: {}", path.display())
+    }
+
+    /// Deixa de partilhar `name`, sem o apagar do disco. Os ficheiros das
+    /// raízes voltam a ser partilhados quando o nó reinicia (ou com
+    /// `rescan`).
+    pub fn unshare(&self, name: &str) -> Option<FileMeta> {
+        let mut files = self.files.write().unwrap();
+        files.remove(name).map(|f| f.meta)
+    }
+
+    /// Caminho de `name` no disco (o `.part`, se estiver incompleto).
+    pub fn path(&self, name: &str) -> Option<PathBuf> {
+        let files = self.files.read().unwrap();
+        files.get(name).map(|f| f.path.clone())
+    }
+
+    /// Passa um download terminado a ficheiro completo: renomeia o
+    /// `.part` e apaga o estado.
+    pub fn complete(&self, name: &str) -> anyhow::Result<FileMeta> {
+        if !is_valid_name(name) {
+            bail!("Invalid file name: {}", name);
+        }
+        let (root, rest) = self.target(name);
+        let path = self.roots[root].path.join(rest);
+        fs::rename(self.part_path(name), &path)?;
+        let meta = {
+            let mut files = self.files.write().unwrap();
+            let Some(f) = files.get_mut(name) else {
+                bail!("Unknown file: {}", name);
+            };
+            f.path = path;
+            f.root = root;
+            f.meta.has_full_file = true;
+            f.meta.clone()
+        };
+        // O ficheiro já está completo; um estado que fique para trás é
+        // ignorado quando o nó reiniciar
+        let state = self.state_path(name);
+        if let Err(e) = fs::remove_file(&state) {
+            println!("Couldn't remove {}: {}", state.display(), e);
+        }
+        Ok(meta)
+    }
+}
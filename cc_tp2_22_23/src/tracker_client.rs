@@ -0,0 +1,217 @@
+//! Cliente do protocolo do tracker, para o nó e para outras ferramentas
+//! que precisem de anunciar ou procurar ficheiros.
+//!
+//! Cada pedido é uma mensagem FSTP (`[flag][data_size u16][dados]`);
+//! `List` e `File` têm uma resposta `Ok` (ou `Error`, se não couber numa
+//! mensagem), os restantes não têm resposta.
+use crate::codec::{CodecError, Encode};
+use crate::file_meta::FileMeta;
+use crate::fstp::{Flag, FstpMessage};
+use crate::load::NodeLoad;
+use crate::peers_with_blocks::PeersWithFile;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+
+/// Dados de uma mensagem (`data_size` é um u16).
+const MAX_DATA: usize = u16::MAX as usize;
+
+#[derive(Debug)]
+pub enum TrackerError {
+    /// Falha na ligação ao tracker.
+    Io(io::Error),
+    /// O tracker fechou a ligação.
+    Disconnected,
+    /// O tracker não conhece o ficheiro.
+    UnknownFile(String),
+    /// Resposta que não se consegue descodificar, ou pedido que não se
+    /// consegue codificar (p.e. grande demais).
+    Codec(CodecError),
+    /// O tracker não conseguiu responder ao pedido, pelo motivo dado.
+    Refused(String),
+    /// O tracker respondeu com outra flag que não `Ok`.
+    UnexpectedResponse(Flag),
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrackerError::Io(e) => {
+                write!(f, "Tracker connection failed: {}", e)
+            }
+            TrackerError::Disconnected => {
+                write!(f, "Tracker no longer reachable")
+            }
+            TrackerError::UnknownFile(name) => {
+                write!(f, "Unknown file: {}", name)
+            }
+            TrackerError::Codec(e) => {
+                write!(f, "Invalid tracker message: {}", e)
+            }
+            TrackerError::Refused(reason) => {
+                write!(f, "Tracker refused the request: {}", reason)
+            }
+            TrackerError::UnexpectedResponse(flag) => {
+                write!(f, "Unexpected {:?} response from tracker", flag)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TrackerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TrackerError::Io(e) => Some(e),
+            TrackerError::Codec(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<CodecError> for TrackerError {
+    fn from(e: CodecError) -> Self {
+        TrackerError::Codec(e)
+    }
+}
+
+impl TrackerError {
+    /// Se a ligação ao tracker se perdeu; nos outros casos pode continuar
+    /// a ser usada.
+    pub fn is_disconnect(&self) -> bool {
+        matches!(self, TrackerError::Io(_) | TrackerError::Disconnected)
+    }
+}
+
+impl From<io::Error> for TrackerError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => TrackerError::Disconnected,
+            _ => TrackerError::Io(e),
+        }
+    }
+}
+
+/// Ligação de um nó ao tracker.
+#[derive(Debug)]
+pub struct TrackerClient {
+    stream: TcpStream,
+}
+
+impl TrackerClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, TrackerError> {
+        Ok(TrackerClient {
+            stream: TcpStream::connect(addr)?,
+        })
+    }
+
+    /// Outra ligação para o mesmo socket, p.e. para `wait_closed`.
+    pub fn try_clone(&self) -> Result<Self, TrackerError> {
+        Ok(TrackerClient {
+            stream: self.stream.try_clone()?,
+        })
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr, TrackerError> {
+        Ok(self.stream.peer_addr()?)
+    }
+
+    /// Porta onde este nó serve blocos aos peers; sem ela o tracker
+    /// indica `TRANSFER_PORT`.
+    pub fn set_port(&mut self, port: u16) -> Result<(), TrackerError> {
+        self.send(Flag::Port, &port.to_be_bytes())
+    }
+
+    /// Anuncia ficheiros, completos ou não. Um novo anúncio de um
+    /// ficheiro substitui o anterior.
+    pub fn announce(&mut self, files: &[FileMeta]) -> Result<(), TrackerError> {
+        let mut data = Vec::new();
+        for meta in files {
+            let len = meta.encoded_len();
+            if len > MAX_DATA {
+                return Err(CodecError::TooLarge { len, max: MAX_DATA }.into());
+            }
+            // Um anúncio grande vai em várias mensagens
+            if data.len() + len > MAX_DATA {
+                self.send(Flag::Add, &data)?;
+                data.clear();
+            }
+            meta.encode(&mut data)?;
+        }
+        if !data.is_empty() {
+            self.send(Flag::Add, &data)?;
+        }
+        Ok(())
+    }
+
+    /// Nomes de todos os ficheiros que o tracker conhece.
+    pub fn list(&mut self) -> Result<Vec<String>, TrackerError> {
+        self.send(Flag::List, &[])?;
+        let data = self.receive()?;
+        if data.is_empty() {
+            return Ok(Vec::new());
+        }
+        let names = String::from_utf8(data)
+            .map_err(|_| CodecError::InvalidUtf8("file list"))?;
+        Ok(names.split(',').map(String::from).collect())
+    }
+
+    /// Peers com o ficheiro, ou com alguns dos seus blocos.
+    pub fn locate(
+        &mut self,
+        name: &str,
+    ) -> Result<PeersWithFile, TrackerError> {
+        self.send(Flag::File, name.as_bytes())?;
+        let data = self.receive()?;
+        if data.is_empty() {
+            return Err(TrackerError::UnknownFile(name.to_string()));
+        }
+        Ok(PeersWithFile::from_bytes(&data)?)
+    }
+
+    /// Deixa de partilhar os ficheiros `names`.
+    pub fn withdraw(&mut self, names: &[&str]) -> Result<(), TrackerError> {
+        self.send(Flag::Remove, names.join(",").as_bytes())
+    }
+
+    pub fn report_load(&mut self, load: &NodeLoad) -> Result<(), TrackerError> {
+        self.send(Flag::Stats, &load.to_bytes())
+    }
+
+    /// Sai da rede: o tracker esquece os ficheiros anunciados.
+    pub fn shutdown(&self) -> Result<(), TrackerError> {
+        Ok(self.stream.shutdown(Shutdown::Both)?)
+    }
+
+    /// Espera que o tracker feche a ligação. Só pode ser usado enquanto
+    /// não houver pedidos com resposta, que seria lida aqui.
+    pub fn wait_closed(&mut self) -> Result<(), TrackerError> {
+        while self.stream.read(&mut [0u8; 64])? > 0 {}
+        Ok(())
+    }
+
+    fn send(&mut self, flag: Flag, data: &[u8]) -> Result<(), TrackerError> {
+        let msg = FstpMessage::new(flag, data)?;
+        self.stream.write_all(&msg.to_vec()?)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    // Dados da resposta `Ok` ao último pedido
+    fn receive(&mut self) -> Result<Vec<u8>, TrackerError> {
+        let mut header = [0u8; 3];
+        self.stream.read_exact(&mut header)?;
+        let data_size = u16::from_be_bytes([header[1], header[2]]) as usize;
+        let mut data = vec![0u8; data_size];
+        self.stream.read_exact(&mut data)?;
+        let mut msg = header.to_vec();
+        msg.extend_from_slice(&data);
+        match FstpMessage::from_bytes(&msg)?.header.flag {
+            Flag::Ok => Ok(data),
+            Flag::Error => {
+                let reason = String::from_utf8_lossy(&data).into_owned();
+                Err(TrackerError::Refused(reason))
+            }
+            flag => Err(TrackerError::UnexpectedResponse(flag)),
+        }
+    }
+}
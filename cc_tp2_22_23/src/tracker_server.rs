@@ -0,0 +1,561 @@
+//! Servidor do tracker: um só thread espera por todas as ligações dos
+//! nós (mio) e as mensagens completas são processadas por uma pool
+//! limitada de workers, que devolvem as respostas ao event loop.
+//!
+//! Pode ser lançado numa thread (`TrackerServer::spawn`) e parado por um
+//! `TrackerHandle`, p.e. em testes.
+//!
+//! Uma mensagem que não se consegue descodificar fecha a ligação: o nó
+//! podia estar à espera de uma resposta que nunca chegaria. Uma resposta
+//! que não cabe numa mensagem não é culpa do nó: vai uma `Flag::Error`
+//! no seu lugar e a ligação (com os ficheiros anunciados) continua.
+//!
+//! As mensagens e as respostas usam buffers de uma `BufferPool`. Cada
+//! resposta é codificada pelo worker diretamente no buffer que o event
+//! loop escreve no socket: se a ligação não tiver nada por enviar, o
+//! buffer passa a ser o de saída, sem cópias.
+use crate::codec::{utf8, CodecError, Encode, Reader};
+use crate::config::TrackerConfig;
+use crate::file_meta::FileMeta;
+use crate::fstp::*;
+use crate::load::NodeLoad;
+use crate::peers_with_blocks::PeersWithFile;
+use crate::pool::BufferPool;
+use crate::ranking::{self, Candidate};
+use crate::tracker_state::{Holder, TrackerState};
+use anyhow::{anyhow, Context};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use threadpool::ThreadPool;
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const FIRST_CONN: Token = Token(2);
+/// Mensagens processadas em simultâneo, independentemente do número de
+/// nós ligados.
+const WORKERS: usize = 4;
+/// Mensagens de uma ligação à espera de um worker. Com mais do que isto,
+/// ou com `MAX_UNSENT` bytes de respostas por enviar (um nó que não as
+/// lê), a ligação deixa de ser lida até esvaziar.
+const MAX_QUEUED: usize = 64;
+const MAX_UNSENT: usize = 256 * 1024;
+
+/// Estado partilhado pelo event loop e pelos workers.
+struct Tracker {
+    state: Arc<TrackerState>,
+    config: TrackerConfig,
+    pool: BufferPool,
+}
+
+/// Ligação de um nó, gerida pelo event loop.
+struct Conn {
+    stream: TcpStream,
+    addr: SocketAddr,
+    // Bytes recebidos que ainda não formam uma mensagem completa
+    inbuf: Vec<u8>,
+    // Mensagens à espera que a anterior seja processada, para as
+    // respostas saírem pela ordem dos pedidos
+    queue: VecDeque<Vec<u8>>,
+    outbuf: Vec<u8>,
+    // Há uma mensagem desta ligação num worker
+    busy: bool,
+    // Deixou de ser lida por ter a `queue` cheia
+    paused: bool,
+    // O nó fechou a ligação
+    closed: bool,
+    writable: bool,
+}
+
+pub struct TrackerServer {
+    poll: Poll,
+    listener: TcpListener,
+    addr: SocketAddr,
+    tracker: Arc<Tracker>,
+    handle: TrackerHandle,
+}
+
+/// Pára o servidor a partir de outra thread.
+#[derive(Clone)]
+pub struct TrackerHandle {
+    waker: Arc<Waker>,
+    stop: Arc<AtomicBool>,
+}
+
+impl TrackerHandle {
+    /// O event loop termina e fecha as ligações; as mensagens que
+    /// estejam nos workers ficam sem resposta.
+    pub fn shutdown(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.waker.wake();
+    }
+}
+
+/// Servidor a correr numa thread própria.
+pub struct RunningTracker {
+    addr: SocketAddr,
+    state: Arc<TrackerState>,
+    handle: TrackerHandle,
+    thread: JoinHandle<anyhow::Result<()>>,
+}
+
+impl RunningTracker {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn state(&self) -> &Arc<TrackerState> {
+        &self.state
+    }
+
+    pub fn handle(&self) -> TrackerHandle {
+        self.handle.clone()
+    }
+
+    /// Pára o servidor e espera que a thread termine.
+    pub fn shutdown(self) -> anyhow::Result<()> {
+        self.handle.shutdown();
+        self.thread
+            .join()
+            .map_err(|_| anyhow!("Tracker thread panicked"))?
+    }
+}
+
+impl TrackerServer {
+    /// Com a porta 0 é escolhida uma porta livre (ver `local_addr`).
+    pub fn bind(
+        addr: SocketAddr,
+        config: TrackerConfig,
+    ) -> anyhow::Result<Self> {
+        let mut listener = TcpListener::bind(addr).context("binding failed")?;
+        let poll = Poll::new()?;
+        poll.registry().register(
+            &mut listener,
+            LISTENER,
+            Interest::READABLE,
+        )?;
+        let handle = TrackerHandle {
+            waker: Arc::new(Waker::new(poll.registry(), WAKER)?),
+            stop: Arc::new(AtomicBool::new(false)),
+        };
+        Ok(TrackerServer {
+            addr: listener.local_addr()?,
+            poll,
+            listener,
+            tracker: Arc::new(Tracker {
+                state: Arc::new(TrackerState::new()),
+                config,
+                pool: BufferPool::new(),
+            }),
+            handle,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn state(&self) -> &Arc<TrackerState> {
+        &self.tracker.state
+    }
+
+    pub fn handle(&self) -> TrackerHandle {
+        self.handle.clone()
+    }
+
+    pub fn spawn(self) -> RunningTracker {
+        RunningTracker {
+            addr: self.addr,
+            state: self.tracker.state.clone(),
+            handle: self.handle.clone(),
+            thread: thread::spawn(move || self.run()),
+        }
+    }
+
+    /// Serve os nós até `TrackerHandle::shutdown`.
+    pub fn run(self) -> anyhow::Result<()> {
+        let TrackerServer {
+            mut poll,
+            listener,
+            tracker,
+            handle: TrackerHandle { waker, stop },
+            ..
+        } = self;
+        let mut events = Events::with_capacity(1024);
+        let (tx, rx) = mpsc::channel::<(Token, Result<Vec<u8>, CodecError>)>();
+        let t_pool = ThreadPool::new(WORKERS);
+
+        let mut conns: HashMap<Token, Conn> = HashMap::new();
+        let mut next_token = FIRST_CONN;
+        loop {
+            if let Err(e) = poll.poll(&mut events, None) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e.into());
+            }
+            if stop.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            // Ligações com trabalho a fazer depois dos eventos
+            let mut touched = HashSet::new();
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => loop {
+                        let (mut stream, addr) = match listener.accept() {
+                            Ok(accepted) => accepted,
+                            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                                break
+                            }
+                            Err(e) => {
+                                println!("Couldn't accept connection: {}", e);
+                                break;
+                            }
+                        };
+                        println!("new connection");
+                        tracker.state.connect(addr);
+                        let token = next_token;
+                        next_token = Token(next_token.0 + 1);
+                        poll.registry().register(
+                            &mut stream,
+                            token,
+                            Interest::READABLE,
+                        )?;
+                        conns.insert(
+                            token,
+                            Conn {
+                                stream,
+                                addr,
+                                inbuf: Vec::new(),
+                                queue: VecDeque::new(),
+                                outbuf: Vec::new(),
+                                busy: false,
+                                paused: false,
+                                closed: false,
+                                writable: false,
+                            },
+                        );
+                    },
+                    WAKER => {}
+                    token => {
+                        if let Some(conn) = conns.get_mut(&token) {
+                            if event.is_readable() {
+                                conn.read(&tracker.pool);
+                            }
+                            touched.insert(token);
+                        }
+                    }
+                }
+            }
+            while let Ok((token, res)) = rx.try_recv() {
+                if let Some(conn) = conns.get_mut(&token) {
+                    conn.busy = false;
+                    match res {
+                        Ok(resp) if conn.outbuf.is_empty() => {
+                            let old = mem::replace(&mut conn.outbuf, resp);
+                            tracker.pool.put(old);
+                        }
+                        Ok(resp) => {
+                            conn.outbuf.extend_from_slice(&resp);
+                            tracker.pool.put(resp);
+                        }
+                        Err(e) => {
+                            println!("{}: {}, closing", conn.addr, e);
+                            conn.queue.clear();
+                            conn.closed = true;
+                        }
+                    }
+                    touched.insert(token);
+                }
+            }
+
+            for token in touched {
+                let Some(conn) = conns.get_mut(&token) else {
+                    continue;
+                };
+                conn.flush();
+                if conn.paused && conn.queue.len() < MAX_QUEUED {
+                    conn.read(&tracker.pool);
+                }
+                let next = if conn.busy || conn.outbuf.len() >= MAX_UNSENT {
+                    None
+                } else {
+                    conn.queue.pop_front()
+                };
+                if let Some(frame) = next {
+                    conn.busy = true;
+                    let (tracker, tx, waker) =
+                        (tracker.clone(), tx.clone(), waker.clone());
+                    let addr = conn.addr;
+                    t_pool.execute(move || {
+                        let mut out = tracker.pool.get();
+                        let res = handle(&frame, addr, &tracker, &mut out);
+                        tracker.pool.put(frame);
+                        let res = match res {
+                            Ok(()) => Ok(out),
+                            Err(e) => {
+                                tracker.pool.put(out);
+                                Err(e)
+                            }
+                        };
+                        let _ = tx.send((token, res));
+                        let _ = waker.wake();
+                    });
+                }
+                if conn.closed && !conn.busy && conn.queue.is_empty() {
+                    let mut conn = conns.remove(&token).unwrap();
+                    poll.registry().deregister(&mut conn.stream)?;
+                    tracker.state.disconnect(conn.addr);
+                    println!("{} connection closed", conn.addr);
+                    continue;
+                }
+                let want_write = !conn.outbuf.is_empty();
+                if want_write != conn.writable {
+                    conn.writable = want_write;
+                    let interest = if want_write {
+                        Interest::READABLE | Interest::WRITABLE
+                    } else {
+                        Interest::READABLE
+                    };
+                    poll.registry().reregister(
+                        &mut conn.stream,
+                        token,
+                        interest,
+                    )?;
+                }
+            }
+        }
+    }
+}
+
+impl Conn {
+    /// Lê tudo o que estiver disponível (até a `queue` encher) e separa
+    /// as mensagens completas (cabeçalho de 3 bytes com o tamanho dos
+    /// dados), em buffers da `pool`.
+    fn read(&mut self, pool: &BufferPool) {
+        let mut buffer = [0u8; 4096];
+        self.paused = false;
+        loop {
+            if self.queue.len() >= MAX_QUEUED {
+                // O resto fica no socket; é lido quando a fila esvaziar
+                self.paused = true;
+                break;
+            }
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    self.closed = true;
+                    break;
+                }
+                Ok(n) => {
+                    self.inbuf.extend_from_slice(&buffer[..n]);
+                    self.split(pool);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => {
+                    self.closed = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn split(&mut self, pool: &BufferPool) {
+        let mut start = 0;
+        while let Some(header) = self.inbuf.get(start..start + 3) {
+            let data_size = u16::from_be_bytes([header[1], header[2]]);
+            let end = start + 3 + data_size as usize;
+            let Some(bytes) = self.inbuf.get(start..end) else {
+                break;
+            };
+            let mut frame = pool.get();
+            frame.extend_from_slice(bytes);
+            self.queue.push_back(frame);
+            start = end;
+        }
+        self.inbuf.drain(..start);
+    }
+
+    fn flush(&mut self) {
+        while !self.outbuf.is_empty() {
+            match self.stream.write(&self.outbuf) {
+                Ok(n) => {
+                    self.outbuf.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => {
+                    self.outbuf.clear();
+                    self.closed = true;
+                }
+            }
+        }
+    }
+}
+
+// Processa uma mensagem da ligação `conn` e devolve a resposta (se
+// houver)
+fn handle(
+    frame: &[u8],
+    conn: SocketAddr,
+    tracker: &Tracker,
+    out: &mut Vec<u8>,
+) -> Result<(), CodecError> {
+    let msg = FstpMessage::from_bytes(frame)?;
+
+    match msg.header.flag {
+        Flag::Add => add(conn, &tracker.state, msg)?,
+        Flag::List => list(out, &tracker.state)?,
+        Flag::File => file(out, conn, &tracker.state, msg, &tracker.config)?,
+        Flag::Stats => stats(conn, &tracker.state, msg)?,
+        Flag::Remove => remove(conn, &tracker.state, msg)?,
+        Flag::Port => port(conn, &tracker.state, msg)?,
+        Flag::Ok | Flag::Error => {} //Em principio não deve de acontecer
+    }
+    Ok(())
+}
+
+// Um anúncio com metadados inválidos é ignorado por inteiro
+fn add(
+    conn: SocketAddr,
+    state: &TrackerState,
+    msg: FstpMessage,
+) -> Result<(), CodecError> {
+    let Some(mut data) = msg.data else {
+        return Ok(());
+    };
+    let mut files_meta = Vec::new();
+    while !data.is_empty() {
+        let (fm_s, fm) = FileMeta::from_bytes(data)?;
+        files_meta.push(fm);
+        data = &data[fm_s..];
+    }
+    state.add(conn, files_meta);
+    Ok(())
+}
+
+fn list(out: &mut Vec<u8>, state: &TrackerState) -> Result<(), CodecError> {
+    let data = state.file_names().join(",");
+    reply(out, data.as_bytes())
+}
+
+// Resposta `Ok` com `data`, ou `Error` se não couber numa mensagem
+fn reply<T: Encode + ?Sized>(
+    out: &mut Vec<u8>,
+    data: &T,
+) -> Result<(), CodecError> {
+    if let Err(e) = encode_message(Flag::Ok, data, out) {
+        println!("Can't send reply: {}", e);
+        encode_message(Flag::Error, e.to_string().as_bytes(), out)?;
+    }
+    Ok(())
+}
+
+fn file(
+    out: &mut Vec<u8>,
+    requester: SocketAddr,
+    state: &TrackerState,
+    msg: FstpMessage,
+    config: &TrackerConfig,
+) -> Result<(), CodecError> {
+    // Sem nome não há resposta possível, mas o nó espera por uma
+    let Some(data) = msg.data else {
+        encode_message(Flag::Error, b"missing file name".as_slice(), out)?;
+        return Ok(());
+    };
+    let file_name = utf8(data, "file name")?.trim_end();
+    println!("Requested file: {}", file_name);
+
+    let mut seeders = HashSet::new();
+    let mut blocks = HashMap::new();
+    let mut candidates = Vec::new();
+    let holders = state.holders(file_name);
+    // Ficheiro desconhecido: resposta sem dados
+    let Some(first) = holders.first() else {
+        encode_message(Flag::Ok, &[][..], out)?;
+        return Ok(());
+    };
+    let n_blocks = first.meta.blocks_len;
+    for Holder {
+        node,
+        addr,
+        meta,
+        load,
+    } in holders
+    {
+        // Quem pede não precisa de se ver na resposta
+        if node == requester {
+            continue;
+        }
+        if meta.has_full_file {
+            seeders.insert(addr);
+        } else {
+            let held = meta.blocks.iter_ones().map(|b| b as u32);
+            blocks.insert(addr, held.collect());
+        }
+        candidates.push(Candidate {
+            addr,
+            seeder: meta.has_full_file,
+            load,
+        });
+    }
+
+    // Só os melhores peers para quem pede, para as respostas não
+    // crescerem com o swarm nem mandarem todos para os mesmos
+    let ranked = ranking::rank(requester.ip(), &candidates, &config.ranking);
+    let (peers_with_file, peers_with_blocks) = ranking::select(
+        &ranked,
+        &seeders,
+        &blocks,
+        config.max_peers_per_file,
+        config.max_peers_per_block,
+    );
+
+    let peers_with_file = PeersWithFile {
+        n_blocks,
+        peers_with_file,
+        peers_with_blocks,
+    };
+
+    reply(out, &peers_with_file)?;
+    Ok(())
+}
+
+fn stats(
+    conn: SocketAddr,
+    state: &TrackerState,
+    msg: FstpMessage,
+) -> Result<(), CodecError> {
+    let data = msg.data.unwrap_or(&[]);
+    state.set_load(conn, NodeLoad::from_bytes(data)?);
+    Ok(())
+}
+
+fn remove(
+    conn: SocketAddr,
+    state: &TrackerState,
+    msg: FstpMessage,
+) -> Result<(), CodecError> {
+    let Some(data) = msg.data else {
+        return Err(CodecError::Truncated("file names"));
+    };
+    let names: Vec<&str> = utf8(data, "file names")?.split(',').collect();
+    state.remove(conn, &names);
+    Ok(())
+}
+
+fn port(
+    conn: SocketAddr,
+    state: &TrackerState,
+    msg: FstpMessage,
+) -> Result<(), CodecError> {
+    let port = Reader::new(msg.data.unwrap_or(&[])).u16("port")?;
+    state.set_port(conn, port);
+    Ok(())
+}
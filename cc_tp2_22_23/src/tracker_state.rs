@@ -0,0 +1,196 @@
+//! Estado do tracker: os ficheiros anunciados por cada nó e, para cada
+//! ficheiro, os nós que o têm.
+//!
+//! Cada nó é identificado pelo endereço da sua ligação ao tracker, e não
+//! só pelo IP, para que vários nós possam correr na mesma máquina; os
+//! peers recebem o endereço onde o nó serve blocos (`TrackedNode::addr`).
+//!
+//! Os dois índices ficam sob o mesmo `RwLock` e são alterados juntos,
+//! para nunca se contradizerem. As consultas só precisam do lock de
+//! leitura e podem correr em simultâneo. Os metadados são partilhados
+//! pelos dois índices e pelas consultas (`Arc`), e não copiados.
+use crate::file_meta::FileMeta;
+use crate::load::NodeLoad;
+use crate::transfer::TRANSFER_PORT;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+/// Cargas mais antigas do que isto já não contam na escolha de peers.
+const LOAD_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// Um nó ligado: os ficheiros que anunciou e a última carga reportada.
+#[derive(Debug, Clone)]
+pub struct TrackedNode {
+    /// Onde o nó serve blocos: o IP da ligação e a porta que anunciou.
+    pub addr: SocketAddr,
+    pub files: Vec<Arc<FileMeta>>,
+    pub load: Option<(NodeLoad, Instant)>,
+}
+
+impl TrackedNode {
+    fn new(conn: SocketAddr) -> Self {
+        TrackedNode {
+            addr: SocketAddr::new(conn.ip(), TRANSFER_PORT),
+            files: Vec::new(),
+            load: None,
+        }
+    }
+
+    /// Carga entre 0 (livre) e 1 (saturado); 0 se não houver uma recente.
+    pub fn load_score(&self) -> f64 {
+        match self.load {
+            Some((load, at)) if at.elapsed() < LOAD_MAX_AGE => load.score(),
+            _ => 0.0,
+        }
+    }
+}
+
+/// Um nó com um ficheiro, como devolvido por `TrackerState::holders`.
+#[derive(Debug, Clone)]
+pub struct Holder {
+    /// Ligação do nó ao tracker.
+    pub node: SocketAddr,
+    /// Onde pedir os blocos.
+    pub addr: SocketAddr,
+    pub meta: Arc<FileMeta>,
+    pub load: f64,
+}
+
+#[derive(Debug, Default)]
+struct Indexes {
+    nodes: HashMap<SocketAddr, TrackedNode>,
+    files: HashMap<String, Vec<(SocketAddr, Arc<FileMeta>)>>,
+}
+
+impl Indexes {
+    fn node(&mut self, conn: SocketAddr) -> &mut TrackedNode {
+        self.nodes
+            .entry(conn)
+            .or_insert_with(|| TrackedNode::new(conn))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TrackerState {
+    inner: RwLock<Indexes>,
+}
+
+impl TrackerState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // As alterações não entram em pânico a meio, por isso um lock
+    // envenenado tem os índices consistentes e pode continuar a ser usado
+    fn read(&self) -> RwLockReadGuard<'_, Indexes> {
+        self.inner.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Indexes> {
+        self.inner.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Nova ligação de um nó.
+    pub fn connect(&self, conn: SocketAddr) {
+        self.write().node(conn);
+    }
+
+    /// O nó saiu e deixa de ser indicado como tendo os seus ficheiros.
+    pub fn disconnect(&self, conn: SocketAddr) {
+        let mut state = self.write();
+        if state.nodes.remove(&conn).is_none() {
+            return;
+        }
+        state.files.retain(|_, holders| {
+            holders.retain(|(c, _)| *c != conn);
+            !holders.is_empty()
+        });
+    }
+
+    /// Porta onde o nó serve blocos; sem ela fica `TRANSFER_PORT`.
+    pub fn set_port(&self, conn: SocketAddr, port: u16) {
+        self.write().node(conn).addr.set_port(port);
+    }
+
+    /// Regista os ficheiros anunciados pelo nó. Um novo anúncio do mesmo
+    /// ficheiro substitui o anterior (p.e. um download parcial que
+    /// terminou).
+    pub fn add(&self, conn: SocketAddr, metas: Vec<FileMeta>) {
+        let mut guard = self.write();
+        let state = &mut *guard;
+        let node = state
+            .nodes
+            .entry(conn)
+            .or_insert_with(|| TrackedNode::new(conn));
+        for meta in metas {
+            let meta = Arc::new(meta);
+            match node.files.iter().position(|fm| fm.name == meta.name) {
+                Some(pos) => node.files[pos] = meta.clone(),
+                None => node.files.push(meta.clone()),
+            }
+            let holders = state.files.entry(meta.name.clone()).or_default();
+            match holders.iter().position(|(c, _)| *c == conn) {
+                Some(pos) => holders[pos].1 = meta,
+                None => holders.push((conn, meta)),
+            }
+        }
+    }
+
+    /// O nó deixou de partilhar os ficheiros `names`.
+    pub fn remove(&self, conn: SocketAddr, names: &[&str]) {
+        let mut guard = self.write();
+        let state = &mut *guard;
+        if let Some(node) = state.nodes.get_mut(&conn) {
+            node.files.retain(|fm| !names.contains(&fm.name.as_str()));
+        }
+        for name in names {
+            if let Some(holders) = state.files.get_mut(*name) {
+                holders.retain(|(c, _)| *c != conn);
+                if holders.is_empty() {
+                    state.files.remove(*name);
+                }
+            }
+        }
+    }
+
+    pub fn set_load(&self, conn: SocketAddr, load: NodeLoad) {
+        self.write().node(conn).load = Some((load, Instant::now()));
+    }
+
+    /// Nomes de todos os ficheiros disponíveis.
+    pub fn file_names(&self) -> Vec<String> {
+        self.read().files.keys().cloned().collect()
+    }
+
+    /// Nós com o ficheiro `name`, com a carga de cada um.
+    pub fn holders(&self, name: &str) -> Vec<Holder> {
+        let state = self.read();
+        let Some(holders) = state.files.get(name) else {
+            return Vec::new();
+        };
+        holders
+            .iter()
+            .filter_map(|(conn, meta)| {
+                let node = state.nodes.get(conn)?;
+                Some(Holder {
+                    node: *conn,
+                    addr: node.addr,
+                    meta: Arc::clone(meta),
+                    load: node.load_score(),
+                })
+            })
+            .collect()
+    }
+
+    /// Cópia dos nós ligados, pela ligação de cada um.
+    pub fn nodes(&self) -> Vec<(SocketAddr, TrackedNode)> {
+        let state = self.read();
+        state
+            .nodes
+            .iter()
+            .map(|(conn, node)| (*conn, node.clone()))
+            .collect()
+    }
+}
@@ -0,0 +1,540 @@
+//! Protocolo de transferência de blocos entre nós, sobre TCP ou sobre
+//! mensagens fiáveis em UDP (`reliable`), com as mesmas mensagens.
+//!
+//! Pedido: `[tipo u8][name_len u16][name]`, seguido de `[bloco u32]`
+//! nos pedidos de bloco. Resposta: `[tipo u8][len u32][dados]`.
+//! O ping é só `[tipo u8]`, e a resposta não tem dados; serve para medir
+//! o RTT até ao peer. Em UDP cada pedido e cada resposta é uma mensagem.
+//!
+//! O `len` de uma resposta vem do peer: acima do máximo do tipo de
+//! resposta é rejeitado antes de ser alocado.
+use crate::file_meta::BLOCK_SIZE;
+use crate::load::UploadStats;
+use crate::ratelimit::{RateLimiter, Throttled};
+use crate::reliable::{self, ReliableSocket};
+use crate::store::{FileInfo, FileStore};
+use anyhow::bail;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use threadpool::ThreadPool;
+
+/// Porta por omissão onde os nós servem os blocos.
+pub const TRANSFER_PORT: u16 = 9090;
+
+const REQ_INFO: u8 = 1;
+const REQ_BLOCK: u8 = 2;
+const REQ_PING: u8 = 3;
+
+const RESP_NOT_FOUND: u8 = 0;
+const RESP_INFO: u8 = 1;
+const RESP_BLOCK: u8 = 2;
+const RESP_PONG: u8 = 3;
+
+/// Ligações (TCP) ou pedidos (UDP) atendidos ao mesmo tempo.
+const SERVE_THREADS: usize = 32;
+/// À espera de uma thread livre; para além disto as ligações são
+/// fechadas e os pedidos ignorados.
+const MAX_QUEUED: usize = 4 * SERVE_THREADS;
+/// Uma ligação sem pedidos (ou sem ler a resposta) há mais do que isto é
+/// fechada.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// f_size + block_size, antes dos digests.
+const INFO_HEADER_LEN: usize = 12;
+const DIGEST_LEN: usize = 20;
+/// Blocos de um ficheiro descrito numa resposta de info: até 1 TiB com
+/// blocos de `BLOCK_SIZE`.
+const MAX_INFO_BLOCKS: usize = 1 << 22;
+
+/// Sockets UDP por usar, guardados por peer, no máximo.
+const MAX_IDLE_SOCKETS: usize = 16;
+/// Tempo ao fim do qual um socket por usar é fechado.
+const SOCKET_IDLE_AFTER: Duration = Duration::from_secs(60);
+
+/// Como são feitos os pedidos a outros nós.
+#[derive(Debug, Clone)]
+pub enum Transport {
+    Tcp,
+    /// Mensagens fiáveis sobre UDP, com controlo de congestão.
+    Udp(Arc<UdpSockets>),
+}
+
+impl Transport {
+    pub fn udp(config: reliable::Config) -> Self {
+        Transport::Udp(Arc::new(UdpSockets::new(config)))
+    }
+}
+
+/// Socket livre e desde quando.
+type IdleSocket = (Arc<ReliableSocket>, Instant);
+
+/// Sockets UDP de cliente, reutilizados de pedido para pedido ao mesmo
+/// peer. O peer guarda a janela de congestão e o RTT por socket: com um
+/// socket novo por bloco, cada bloco recomeçava em slow start e o peer
+/// acumulava uma entrada por porta.
+pub struct UdpSockets {
+    config: reliable::Config,
+    idle: Mutex<HashMap<SocketAddr, Vec<IdleSocket>>>,
+}
+
+impl std::fmt::Debug for UdpSockets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UdpSockets")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl UdpSockets {
+    pub fn new(config: reliable::Config) -> Self {
+        UdpSockets {
+            config,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Um socket livre para `peer`, ou um novo.
+    fn get(&self, peer: SocketAddr) -> io::Result<Arc<ReliableSocket>> {
+        let reused = {
+            let mut idle = self.idle.lock().unwrap();
+            let now = Instant::now();
+            idle.retain(|_, sockets| {
+                sockets.retain(|(_, t)| now - *t < SOCKET_IDLE_AFTER);
+                !sockets.is_empty()
+            });
+            idle.get_mut(&peer).and_then(Vec::pop)
+        };
+        if let Some((socket, _)) = reused {
+            return Ok(socket);
+        }
+        let local = match peer {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = ReliableSocket::bind(local, self.config.clone())?;
+        Ok(Arc::new(socket))
+    }
+
+    /// Devolve um socket sem pedidos por responder. Os fechados (por
+    /// um cancelamento) são largados.
+    fn put(&self, peer: SocketAddr, socket: Arc<ReliableSocket>) {
+        if socket.is_closed() {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        let sockets = idle.entry(peer).or_default();
+        if sockets.len() < MAX_IDLE_SOCKETS {
+            sockets.push((socket, Instant::now()));
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Request {
+    Info { name: String },
+    Block { name: String, block: u32 },
+    Ping,
+}
+
+#[derive(Debug)]
+pub enum Response {
+    NotFound,
+    Info(FileInfo),
+    Block(Vec<u8>),
+    Pong,
+}
+
+impl Request {
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let (kind, name) = match self {
+            Request::Info { name } => (REQ_INFO, name),
+            Request::Block { name, .. } => (REQ_BLOCK, name),
+            Request::Ping => {
+                w.write_all(&[REQ_PING])?;
+                return w.flush();
+            }
+        };
+        let mut buf = vec![kind];
+        buf.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        buf.extend_from_slice(name.as_bytes());
+        if let Request::Block { block, .. } = self {
+            buf.extend_from_slice(&block.to_be_bytes());
+        }
+        w.write_all(&buf)?;
+        w.flush()
+    }
+
+    /// `None` quando o outro lado fechou a ligação.
+    pub fn read_from<R: Read>(r: &mut R) -> anyhow::Result<Option<Request>> {
+        let mut kind = [0u8; 1];
+        if r.read(&mut kind)? == 0 {
+            return Ok(None);
+        }
+        if kind[0] == REQ_PING {
+            return Ok(Some(Request::Ping));
+        }
+        let mut b_name_len = [0u8; 2];
+        r.read_exact(&mut b_name_len)?;
+        let mut b_name = vec![0u8; u16::from_be_bytes(b_name_len) as usize];
+        r.read_exact(&mut b_name)?;
+        let name = String::from_utf8(b_name)?;
+        match kind[0] {
+            REQ_INFO => Ok(Some(Request::Info { name })),
+            REQ_BLOCK => {
+                let mut b_block = [0u8; 4];
+                r.read_exact(&mut b_block)?;
+                let block = u32::from_be_bytes(b_block);
+                Ok(Some(Request::Block { name, block }))
+            }
+            _ => bail!("Invalid request type"),
+        }
+    }
+}
+
+impl Response {
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut payload = Vec::new();
+        let kind = match self {
+            Response::NotFound => RESP_NOT_FOUND,
+            Response::Info(info) => {
+                payload.extend_from_slice(&info.f_size.to_be_bytes());
+                payload.extend_from_slice(&info.block_size.to_be_bytes());
+                for d in &info.digests {
+                    payload.extend_from_slice(d);
+                }
+                RESP_INFO
+            }
+            Response::Block(data) => {
+                payload.extend_from_slice(data);
+                RESP_BLOCK
+            }
+            Response::Pong => RESP_PONG,
+        };
+        w.write_all(&[kind])?;
+        w.write_all(&(payload.len() as u32).to_be_bytes())?;
+        w.write_all(&payload)?;
+        w.flush()
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> anyhow::Result<Response> {
+        let mut header = [0u8; 5];
+        r.read_exact(&mut header)?;
+        let len = u32::from_be_bytes(header[1..5].try_into().unwrap());
+        let max = match header[0] {
+            RESP_INFO => INFO_HEADER_LEN + DIGEST_LEN * MAX_INFO_BLOCKS,
+            RESP_BLOCK => BLOCK_SIZE as usize,
+            _ => 0,
+        };
+        if len as usize > max {
+            bail!("Response too large: {} bytes (max {})", len, max);
+        }
+        // Cresce com os bytes que chegam, não com o `len` anunciado
+        let mut payload = Vec::new();
+        r.take(len as u64).read_to_end(&mut payload)?;
+        if payload.len() != len as usize {
+            bail!("Response truncated: {} of {} bytes", payload.len(), len);
+        }
+        match header[0] {
+            RESP_NOT_FOUND => Ok(Response::NotFound),
+            RESP_INFO => {
+                let digests_len = payload.len().checked_sub(INFO_HEADER_LEN);
+                if !digests_len.is_some_and(|n| n.is_multiple_of(DIGEST_LEN)) {
+                    bail!("Invalid info response");
+                }
+                let f_size = u64::from_be_bytes(payload[..8].try_into()?);
+                let block_size = u32::from_be_bytes(payload[8..12].try_into()?);
+                let digests = payload[INFO_HEADER_LEN..]
+                    .chunks_exact(DIGEST_LEN)
+                    .map(|c| c.try_into().unwrap())
+                    .collect();
+                Ok(Response::Info(FileInfo {
+                    f_size,
+                    block_size,
+                    digests,
+                }))
+            }
+            RESP_BLOCK => Ok(Response::Block(payload)),
+            RESP_PONG => Ok(Response::Pong),
+            _ => bail!("Invalid response type"),
+        }
+    }
+}
+
+/// Aceita ligações de outros nós e serve-lhes blocos do `store`, com o
+/// upload limitado por `limiter` e contado em `stats`.
+pub fn serve(
+    listener: TcpListener,
+    store: Arc<FileStore>,
+    limiter: Arc<RateLimiter>,
+    stats: Arc<UploadStats>,
+) {
+    let pool = ThreadPool::new(SERVE_THREADS);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(_) if pool.queued_count() >= MAX_QUEUED => {
+                println!("Too many peers, closing a connection");
+            }
+            Ok(stream) => {
+                let store = store.clone();
+                let limiter = limiter.clone();
+                let stats = stats.clone();
+                pool.execute(move || {
+                    let res = handle_peer(stream, &store, limiter, &stats);
+                    if let Err(e) = res {
+                        println!("transfer error: {}", e);
+                    }
+                });
+            }
+            Err(e) => println!("Couldn't accept peer: {}", e),
+        }
+    }
+}
+
+fn handle_peer(
+    stream: TcpStream,
+    store: &FileStore,
+    limiter: Arc<RateLimiter>,
+    stats: &UploadStats,
+) -> anyhow::Result<()> {
+    let peer = stream.peer_addr()?.ip();
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    let mut stream = Throttled::new(stream, limiter, peer);
+    while let Some(req) = Request::read_from(&mut stream)? {
+        let mut upload = stats.upload();
+        let mut buf = Vec::new();
+        respond(store, req)?.write_to(&mut buf)?;
+        upload.start();
+        stream.write_all(&buf)?;
+        stream.flush()?;
+        upload.sent(buf.len());
+    }
+    Ok(())
+}
+
+fn respond(store: &FileStore, req: Request) -> anyhow::Result<Response> {
+    let resp = match req {
+        Request::Info { name } => match store.info(&name)? {
+            Some(info) => Response::Info(info),
+            None => Response::NotFound,
+        },
+        Request::Block { name, block } => {
+            match store.read_block(&name, block)? {
+                Some(data) => Response::Block(data),
+                None => Response::NotFound,
+            }
+        }
+        Request::Ping => Response::Pong,
+    };
+    Ok(resp)
+}
+
+/// Como `serve`, mas com os pedidos a chegar por UDP. Termina quando o
+/// socket é fechado.
+pub fn serve_udp(
+    socket: Arc<ReliableSocket>,
+    store: Arc<FileStore>,
+    limiter: Arc<RateLimiter>,
+    stats: Arc<UploadStats>,
+) {
+    let pool = ThreadPool::new(SERVE_THREADS);
+    while let Ok((msg, peer)) = socket.recv_from() {
+        // O peer volta a pedir quando o seu timeout expirar
+        if pool.queued_count() >= MAX_QUEUED {
+            continue;
+        }
+        let socket = socket.clone();
+        let store = store.clone();
+        let limiter = limiter.clone();
+        let stats = stats.clone();
+        // O envio da resposta bloqueia até ser toda confirmada
+        pool.execute(move || {
+            let res = handle_udp_request(
+                &socket, &store, &limiter, &stats, &msg, peer,
+            );
+            if let Err(e) = res {
+                println!("transfer error: {}", e);
+            }
+        });
+    }
+}
+
+fn handle_udp_request(
+    socket: &ReliableSocket,
+    store: &FileStore,
+    limiter: &RateLimiter,
+    stats: &UploadStats,
+    msg: &[u8],
+    peer: SocketAddr,
+) -> anyhow::Result<()> {
+    let Some(req) = Request::read_from(&mut &msg[..])? else {
+        return Ok(());
+    };
+    let mut upload = stats.upload();
+    let mut buf = Vec::new();
+    respond(store, req)?.write_to(&mut buf)?;
+    limiter.consume(peer.ip(), buf.len());
+    upload.start();
+    socket.send_to(&buf, peer)?;
+    upload.sent(buf.len());
+    Ok(())
+}
+
+/// Ligação a outro nó para pedir informação e blocos de ficheiros.
+pub struct PeerConn {
+    channel: Channel,
+}
+
+enum Channel {
+    Tcp(Throttled<TcpStream>),
+    Udp {
+        socket: Arc<ReliableSocket>,
+        peer: SocketAddr,
+        timeout: Duration,
+        limiter: Arc<RateLimiter>,
+        sockets: Arc<UdpSockets>,
+        // Sem pedido por responder: uma resposta atrasada não pode
+        // chegar ao próximo a usar o socket
+        idle: bool,
+    },
+}
+
+impl PeerConn {
+    /// O download desta ligação fica sujeito a `limiter`.
+    pub fn connect(
+        addr: SocketAddr,
+        timeout: Duration,
+        limiter: Arc<RateLimiter>,
+        transport: &Transport,
+    ) -> io::Result<Self> {
+        let channel = match transport {
+            Transport::Tcp => {
+                let stream = TcpStream::connect_timeout(&addr, timeout)?;
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                Channel::Tcp(Throttled::new(stream, limiter, addr.ip()))
+            }
+            Transport::Udp(sockets) => Channel::Udp {
+                socket: sockets.get(addr)?,
+                peer: addr,
+                timeout,
+                limiter,
+                sockets: sockets.clone(),
+                idle: true,
+            },
+        };
+        Ok(PeerConn { channel })
+    }
+
+    /// Permite interromper esta ligação a partir de outra thread.
+    pub fn canceller(&self) -> io::Result<Canceller> {
+        match &self.channel {
+            Channel::Tcp(stream) => {
+                Ok(Canceller::Tcp(stream.get_ref().try_clone()?))
+            }
+            Channel::Udp { socket, .. } => Ok(Canceller::Udp(socket.clone())),
+        }
+    }
+
+    fn request(&mut self, req: &Request) -> anyhow::Result<Response> {
+        match &mut self.channel {
+            Channel::Tcp(stream) => {
+                req.write_to(stream)?;
+                Response::read_from(stream)
+            }
+            Channel::Udp {
+                socket,
+                peer,
+                timeout,
+                limiter,
+                idle,
+                ..
+            } => {
+                let mut buf = Vec::new();
+                req.write_to(&mut buf)?;
+                *idle = false;
+                socket.send_timeout(&buf, *peer, *timeout)?;
+                loop {
+                    let (msg, from) = socket.recv_timeout(*timeout)?;
+                    if from != *peer {
+                        continue;
+                    }
+                    *idle = true;
+                    limiter.consume(peer.ip(), msg.len());
+                    return Response::read_from(&mut &msg[..]);
+                }
+            }
+        }
+    }
+
+    pub fn info(&mut self, name: &str) -> anyhow::Result<Option<FileInfo>> {
+        let req = Request::Info {
+            name: name.to_string(),
+        };
+        match self.request(&req)? {
+            Response::Info(info) => Ok(Some(info)),
+            Response::NotFound => Ok(None),
+            _ => bail!("Unexpected response"),
+        }
+    }
+
+    pub fn block(
+        &mut self,
+        name: &str,
+        block: u32,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let req = Request::Block {
+            name: name.to_string(),
+            block,
+        };
+        match self.request(&req)? {
+            Response::Block(data) => Ok(Some(data)),
+            Response::NotFound => Ok(None),
+            _ => bail!("Unexpected response"),
+        }
+    }
+
+    /// Mede o tempo de ida e volta de um pedido vazio.
+    pub fn ping(&mut self) -> anyhow::Result<Duration> {
+        let start = Instant::now();
+        match self.request(&Request::Ping)? {
+            Response::Pong => Ok(start.elapsed()),
+            _ => bail!("Unexpected response"),
+        }
+    }
+}
+
+impl Drop for PeerConn {
+    fn drop(&mut self) {
+        if let Channel::Udp {
+            socket,
+            peer,
+            sockets,
+            idle: true,
+            ..
+        } = &self.channel
+        {
+            sockets.put(*peer, socket.clone());
+        }
+    }
+}
+
+/// Interrompe uma `PeerConn`: o pedido em curso, ou o próximo, falha.
+pub enum Canceller {
+    Tcp(TcpStream),
+    Udp(Arc<ReliableSocket>),
+}
+
+impl Canceller {
+    pub fn cancel(&self) {
+        match self {
+            Canceller::Tcp(stream) => {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            Canceller::Udp(socket) => socket.close(),
+        }
+    }
+}
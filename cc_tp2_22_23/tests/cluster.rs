@@ -0,0 +1,407 @@
+mod harness;
+
+use harness::{payload, wait_until, Cluster};
+use local::file_meta::{FileMeta, BLOCK_SIZE};
+use local::store::FileInfo;
+use local::tracker_client::TrackerError;
+use local::transfer::{Request, Response};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Um pouco mais de dois blocos, para o último ficar incompleto
+const BIG: usize = 2 * BLOCK_SIZE as usize + 1000;
+
+fn has_file(cluster: &Cluster, i: usize, name: &str, data: &[u8]) -> bool {
+    fs::read(cluster.shared(i).join(name)).is_ok_and(|d| d == data)
+}
+
+#[test]
+fn announce_list_and_locate() {
+    let mut cluster = Cluster::new();
+    let a = payload(1000, 1);
+    let b = payload(BIG, 2);
+    let n0 = cluster.add_node(&[("a.bin", &a)]);
+    let n1 = cluster.add_node(&[("a.bin", &a), ("b.bin", &b)]);
+
+    wait_until("both nodes to announce", || {
+        cluster.holders("a.bin").len() == 2
+            && cluster.holders("b.bin").len() == 1
+    });
+    let mut client = cluster.client();
+    let mut names = client.list().unwrap();
+    names.sort();
+    assert_eq!(names, ["a.bin", "b.bin"]);
+
+    let peers = client.locate("a.bin").unwrap();
+    let expected = HashSet::from([cluster.addr(n0), cluster.addr(n1)]);
+    assert_eq!(peers.peers_with_file, expected);
+    assert_eq!(peers.n_blocks, 1);
+
+    let peers = client.locate("b.bin").unwrap();
+    assert_eq!(peers.peers_with_file, HashSet::from([cluster.addr(n1)]));
+    assert_eq!(peers.n_blocks, 3);
+
+    match client.locate("missing.bin") {
+        Err(TrackerError::UnknownFile(name)) => assert_eq!(name, "missing.bin"),
+        other => panic!("expected an unknown file, got {:?}", other),
+    }
+}
+
+#[test]
+fn download_from_seeders() {
+    let mut cluster = Cluster::new();
+    let data = payload(BIG, 3);
+    cluster.add_node(&[("big.bin", &data)]);
+    cluster.add_node(&[("big.bin", &data)]);
+    let leecher = cluster.add_node(&[]);
+    wait_until("the seeders to announce", || {
+        cluster.holders("big.bin").len() == 2
+    });
+
+    cluster.ctl(leecher, "get big.bin").unwrap();
+    wait_until("the download", || {
+        has_file(&cluster, leecher, "big.bin", &data)
+    });
+    // Quando termina, o nó anuncia que também tem o ficheiro
+    let addr = cluster.addr(leecher);
+    wait_until("the download to be announced", || {
+        cluster.holders("big.bin").contains(&(addr, true))
+    });
+    assert!(!cluster.shared(leecher).join("big.bin.part").exists());
+
+    // Também sem endgame
+    let plain = cluster.add_node_with(&[], &[], "endgame_blocks = 0\n");
+    cluster.ctl(plain, "get big.bin").unwrap();
+    wait_until("the download without endgame", || {
+        has_file(&cluster, plain, "big.bin", &data)
+    });
+
+    let err = cluster.ctl(leecher, "get big.bin").unwrap_err();
+    assert_eq!(err, "Already have big.bin");
+}
+
+#[test]
+fn unshare_withdraws_the_file() {
+    let mut cluster = Cluster::new();
+    let data = payload(1000, 4);
+    let n0 = cluster.add_node(&[("a.bin", &data)]);
+    wait_until("the announce", || cluster.holders("a.bin").len() == 1);
+
+    cluster.ctl(n0, "unshare a.bin").unwrap();
+    wait_until("the file to be withdrawn", || {
+        cluster.state().file_names().is_empty()
+    });
+    // O ficheiro continua no disco
+    assert!(has_file(&cluster, n0, "a.bin", &data));
+}
+
+#[test]
+fn nested_directories() {
+    let mut cluster = Cluster::new();
+    let a = payload(1000, 6);
+    let b = payload(BIG, 7);
+    cluster.add_node(&[
+        ("c.bin", &a),
+        ("docs/a.bin", &a),
+        ("docs/old/b.bin", &b),
+        ("docs/notes.tmp", &a),
+    ]);
+    let leecher = cluster.add_node(&[]);
+    wait_until("the announce", || cluster.state().file_names().len() == 4);
+    let mut names = cluster.client().list().unwrap();
+    names.sort();
+    assert_eq!(
+        names,
+        ["c.bin", "docs/a.bin", "docs/notes.tmp", "docs/old/b.bin"]
+    );
+
+    // Os separadores são normalizados, e os diretórios criados
+    cluster.ctl(leecher, "get docs\\old\\b.bin").unwrap();
+    wait_until("the download", || {
+        has_file(&cluster, leecher, "docs/old/b.bin", &b)
+    });
+    let addr = cluster.addr(leecher);
+    wait_until("the download to be announced", || {
+        cluster.holders("docs/old/b.bin").contains(&(addr, true))
+    });
+
+    // Nada fora do diretório partilhado
+    for name in ["../b.bin", "/docs/a.bin", "docs/../../a.bin"] {
+        let err = cluster.ctl(leecher, &format!("get {}", name)).unwrap_err();
+        assert_eq!(err, format!("Invalid file name: {}", name));
+    }
+}
+
+#[test]
+fn multiple_roots() {
+    let mut cluster = Cluster::new();
+    let a = payload(1000, 8);
+    let d = payload(BIG, 9);
+    let n0 = cluster.add_node_with(
+        &[("a.bin", &a)],
+        &[
+            ("music/a.bin", &a),
+            ("docs/a.bin", &a),
+            ("docs/sub/d.bin", &d),
+        ],
+        "root = music, ro\nroot = docs, prefix = papers\n",
+    );
+    // Outro nó com as mesmas raízes, mas noutros diretórios
+    let leecher = cluster.add_node_with(
+        &[],
+        &[("music/own.bin", &a), ("inbox/keep.bin", &a)],
+        "root = inbox, prefix = papers\nroot = music, ro\n",
+    );
+    wait_until("the announce", || cluster.state().file_names().len() == 6);
+    let mut names = cluster.client().list().unwrap();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "a.bin",
+            "music/a.bin",
+            "music/own.bin",
+            "papers/a.bin",
+            "papers/keep.bin",
+            "papers/sub/d.bin",
+        ]
+    );
+    let roots = cluster.ctl(leecher, "roots").unwrap();
+    assert!(roots.contains("papers"), "{}", roots);
+
+    // Vai para a raiz com o mesmo prefixo...
+    cluster.ctl(leecher, "get papers/sub/d.bin").unwrap();
+    let path = cluster.dir(leecher).join("inbox/sub/d.bin");
+    wait_until("the download into the root", || {
+        fs::read(&path).is_ok_and(|data| data == d)
+    });
+    // ...mas nunca para uma só de leitura
+    cluster.ctl(leecher, "get music/a.bin").unwrap();
+    wait_until("the download into the shared directory", || {
+        has_file(&cluster, leecher, "music/a.bin", &a)
+    });
+    assert!(!cluster.dir(leecher).join("music/a.bin").exists());
+
+    // Só a raiz indicada é relida
+    let docs = cluster.dir(n0).join("docs");
+    fs::write(docs.join("new.bin"), &a).unwrap();
+    fs::remove_file(docs.join("a.bin")).unwrap();
+    let out = cluster.ctl(n0, "rescan papers").unwrap();
+    assert_eq!(out.trim(), "1 new or changed, 1 removed");
+    let addr = cluster.addr(n0);
+    wait_until("the rescan to be announced", || {
+        cluster.holders("papers/new.bin") == [(addr, true)]
+            && !cluster
+                .holders("papers/a.bin")
+                .iter()
+                .any(|(holder, _)| *holder == addr)
+    });
+    let err = cluster.ctl(n0, "rescan videos").unwrap_err();
+    assert!(err.starts_with("Unknown root: videos"), "{}", err);
+
+    // `share` dá o nome que a raiz daria
+    fs::write(docs.join("sub/late.bin"), &a).unwrap();
+    let path = docs.join("sub/late.bin");
+    let out = cluster
+        .ctl(n0, &format!("share {}", path.display()))
+        .unwrap();
+    assert_eq!(out.trim(), "Sharing papers/sub/late.bin");
+    fs::write(cluster.dir(n0).join("late.bin"), &a).unwrap();
+    let path = cluster.dir(n0).join("late.bin");
+    let err = cluster
+        .ctl(n0, &format!("share {}", path.display()))
+        .unwrap_err();
+    assert!(err.starts_with("Not in a shared root"), "{}", err);
+}
+
+#[test]
+fn root_prefix_clashing_with_the_main_root() {
+    let mut cluster = Cluster::new();
+    let a = payload(1000, 10);
+    let b = payload(2000, 11);
+    let n0 = cluster.add_node_with(
+        &[("papers/a.bin", &a)],
+        &[("docs/a.bin", &b), ("docs/b.bin", &b)],
+        "root = docs, prefix = papers\n",
+    );
+    wait_until("the announce", || cluster.state().file_names().len() == 2);
+    let log = cluster.log(n0);
+    assert!(log.contains("clashes with the root"), "{}", log);
+    assert!(log.contains("papers/a.bin is already shared"), "{}", log);
+
+    // Fica o da principal, também depois de reler a outra raiz
+    let out = cluster.ctl(n0, "rescan papers").unwrap();
+    assert_eq!(out.trim(), "0 new or changed, 0 removed");
+    let path = cluster.shared(n0).join("papers/a.bin");
+    let out = cluster.ctl(n0, &format!("share {}", path.display()));
+    assert_eq!(out.unwrap_err(), "papers/a.bin is already shared");
+    let info = cluster.ctl(n0, "info papers/a.bin").unwrap();
+    assert!(info.contains("shared/papers/a.bin"), "{}", info);
+
+    // Sem o da principal fica o da outra raiz, até a principal ser relida
+    cluster.ctl(n0, "unshare papers/a.bin").unwrap();
+    let out = cluster.ctl(n0, "rescan papers").unwrap();
+    assert_eq!(out.trim(), "1 new or changed, 0 removed");
+    let info = cluster.ctl(n0, "info papers/a.bin").unwrap();
+    assert!(info.contains("docs/a.bin"), "{}", info);
+    let out = cluster.ctl(n0, "rescan -").unwrap();
+    assert_eq!(out.trim(), "1 new or changed, 0 removed");
+    let info = cluster.ctl(n0, "info papers/a.bin").unwrap();
+    assert!(info.contains("shared/papers/a.bin"), "{}", info);
+}
+
+#[test]
+fn sidecars_only_in_writable_roots() {
+    let mut cluster = Cluster::new();
+    let a = payload(1000, 13);
+    // O `.part` deste download é um diretório: não dá para o retomar
+    let state = format!(
+        "name x.bin\nsize 1000\nblock_size 1000\nblocks 0\ndigest {}\n",
+        "00".repeat(20)
+    );
+    let n0 = cluster.add_node_with(
+        &[("x.bin.state", state.as_bytes()), ("x.bin.part/keep", &a)],
+        &[("music/notes.part", &a), ("music/old.state", &a)],
+        "root = music, ro\n",
+    );
+    wait_until("the announce", || cluster.state().file_names().len() == 3);
+    let mut names = cluster.client().list().unwrap();
+    names.sort();
+    assert_eq!(
+        names,
+        ["music/notes.part", "music/old.state", "x.bin.part/keep"]
+    );
+    let log = cluster.log(n0);
+    assert!(log.contains("x.bin.state"), "{}", log);
+}
+
+#[test]
+fn peers_with_inconsistent_info_are_skipped() {
+    let mut cluster = Cluster::new();
+    // Peer que descreve o ficheiro com menos digests do que blocos
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            while let Ok(Some(_)) = Request::read_from(&mut stream) {
+                let info = FileInfo {
+                    f_size: 3 * BLOCK_SIZE as u64,
+                    block_size: BLOCK_SIZE,
+                    digests: vec![[0; 20]],
+                };
+                if Response::Info(info).write_to(&mut stream).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    let mut liar = cluster.client();
+    liar.set_port(port).unwrap();
+    let meta = FileMeta::full(
+        String::from("x.bin"),
+        3 * BLOCK_SIZE as u64,
+        BLOCK_SIZE,
+    );
+    liar.announce(&[meta]).unwrap();
+    let leecher = cluster.add_node(&[]);
+    wait_until("the announce", || cluster.holders("x.bin").len() == 1);
+
+    cluster.ctl(leecher, "get x.bin").unwrap();
+    wait_until("the download to fail", || {
+        cluster
+            .log(leecher)
+            .contains("No peer could describe x.bin")
+    });
+    assert!(cluster.log(leecher).contains("1 digests for 3 blocks"));
+    // O nó continua a responder
+    cluster.ctl(leecher, "status").unwrap();
+}
+
+#[test]
+fn silent_peers_are_disconnected() {
+    let mut cluster = Cluster::new();
+    let n0 = cluster.add_node(&[("a.bin", &payload(1000, 12))]);
+    let addr = cluster.addr(n0);
+
+    // Um pedido a meio, que nunca chega a ser completado
+    let mut idle = TcpStream::connect(addr).unwrap();
+    idle.write_all(&[2, 0]).unwrap();
+    let started = Instant::now();
+    let mut rest = Vec::new();
+    idle.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+    assert!(started.elapsed() < Duration::from_secs(15));
+
+    // Os outros peers continuam a ser servidos
+    let mut peer = TcpStream::connect(addr).unwrap();
+    Request::Ping.write_to(&mut peer).unwrap();
+    assert!(matches!(Response::read_from(&mut peer), Ok(Response::Pong)));
+}
+
+#[test]
+fn udp_downloads_reuse_their_sockets() {
+    let mut cluster = Cluster::new();
+    let a = payload(8 * BLOCK_SIZE as usize, 10);
+    let b = payload(8 * BLOCK_SIZE as usize, 11);
+    let seeder = cluster.add_node(&[("a.bin", &a), ("b.bin", &b)]);
+    let config = "transport = udp\nmax_parallel_blocks = 2\n";
+    let leecher = cluster.add_node_with(&[], &[], config);
+    wait_until("the announce", || cluster.holders("b.bin").len() == 1);
+
+    for (name, data) in [("a.bin", &a), ("b.bin", &b)] {
+        cluster.ctl(leecher, &format!("get {}", name)).unwrap();
+        wait_until("the download", || has_file(&cluster, leecher, name, data));
+    }
+    // O seeder vê um socket por pedido em simultâneo, não um por bloco:
+    // no máximo `max_parallel_blocks`, também no endgame
+    let table = cluster.ctl(seeder, "congestion").unwrap();
+    let links = table.lines().filter(|l| l.contains("127.0.0.1")).count();
+    assert!((1..=2).contains(&links), "{}", table);
+}
+
+#[test]
+fn node_churn() {
+    let mut cluster = Cluster::new();
+    let data = payload(BIG, 5);
+    let seeder = cluster.add_node(&[("big.bin", &data)]);
+    let first = cluster.add_node(&[]);
+    let second = cluster.add_node(&[]);
+    wait_until("the announce", || cluster.holders("big.bin").len() == 1);
+
+    // Um nó que morre deixa de ser indicado aos outros
+    cluster.kill(seeder);
+    wait_until("the seeder to leave", || {
+        cluster.state().file_names().is_empty()
+    });
+    let err = cluster.ctl(first, "get big.bin").unwrap_err();
+    assert_eq!(err, "Unknown file: big.bin");
+
+    // De volta, com outra porta, volta a anunciar o que tem no disco
+    cluster.start(seeder);
+    let addr = cluster.addr(seeder);
+    wait_until("the seeder to come back", || {
+        cluster.holders("big.bin") == [(addr, true)]
+    });
+    cluster.ctl(first, "get big.bin").unwrap();
+    wait_until("the first download", || {
+        has_file(&cluster, first, "big.bin", &data)
+    });
+    let addr = cluster.addr(first);
+    wait_until("the first download to be announced", || {
+        cluster.holders("big.bin").contains(&(addr, true))
+    });
+
+    // Sem o seeder original, o ficheiro vem do nó que o descarregou
+    cluster.kill(seeder);
+    wait_until("the seeder to leave again", || {
+        cluster.holders("big.bin") == [(addr, true)]
+    });
+    cluster.ctl(second, "get big.bin").unwrap();
+    wait_until("the second download", || {
+        has_file(&cluster, second, "big.bin", &data)
+    });
+}
@@ -0,0 +1,531 @@
+use bitvec::prelude::*;
+use local::codec::{CodecError, Decode, Encode};
+use local::config::TrackerConfig;
+use local::file_meta::{bitmap_len, FileMeta};
+use local::fstp::{Flag, FstpHeader, FstpMessage};
+use local::fuzz;
+use local::load::NodeLoad;
+use local::peers_with_blocks::PeersWithFile;
+use local::tracker_client::{TrackerClient, TrackerError};
+use local::tracker_server::TrackerServer;
+use local::transfer::Response;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+const CASES: usize = 500;
+
+// xorshift64*, com semente fixa para os casos serem reproduzíveis
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+fn flag(i: usize) -> Flag {
+    match i % 8 {
+        0 => Flag::Ok,
+        1 => Flag::Add,
+        2 => Flag::List,
+        3 => Flag::File,
+        4 => Flag::Stats,
+        5 => Flag::Remove,
+        6 => Flag::Port,
+        _ => Flag::Error,
+    }
+}
+
+fn encode_message(flag: Flag, data: &[u8]) -> Vec<u8> {
+    FstpMessage::new(flag, data).unwrap().to_vec().unwrap()
+}
+
+fn random_meta(rng: &mut Rng) -> FileMeta {
+    const CHARS: [char; 8] = ['a', 'Z', '9', '_', '.', 'é', 'ç', '文'];
+    let name: String = (0..rng.below(40))
+        .map(|_| CHARS[rng.below(CHARS.len())])
+        .collect();
+    let blocks_len = rng.below(200) as u32;
+    let blocks: BitVec<u8, Msb0> =
+        (0..blocks_len).map(|_| rng.next() % 2 == 1).collect();
+    FileMeta {
+        f_size: rng.next(),
+        has_full_file: rng.next() % 2 == 1,
+        block_size: rng.next() as u32,
+        blocks_len,
+        name_len: name.len() as u16,
+        blocks,
+        name,
+    }
+}
+
+fn encode_meta(meta: &FileMeta) -> Vec<u8> {
+    let buf = meta.to_vec().unwrap();
+    assert_eq!(
+        buf.len(),
+        19 + bitmap_len(meta.blocks_len) + meta.name.len()
+    );
+    buf
+}
+
+fn random_peer(rng: &mut Rng) -> SocketAddr {
+    let ip = [127, 0, rng.below(4) as u8, rng.below(8) as u8];
+    SocketAddr::from((ip, 9000 + rng.below(4) as u16))
+}
+
+fn random_peers(rng: &mut Rng) -> PeersWithFile {
+    let n_blocks = rng.below(20) as u32;
+    let mut peers = PeersWithFile::new(n_blocks);
+    for _ in 0..rng.below(17) {
+        peers.peers_with_file.insert(random_peer(rng));
+    }
+    for block in 0..n_blocks {
+        let holders: HashSet<SocketAddr> =
+            (0..rng.below(5)).map(|_| random_peer(rng)).collect();
+        if !holders.is_empty() {
+            peers.peers_with_blocks.insert(block, holders);
+        }
+    }
+    peers
+}
+
+fn encode_peers(peers: &PeersWithFile) -> Vec<u8> {
+    let buf = peers.to_vec().unwrap();
+    assert_eq!(buf.len(), peers.encoded_len());
+    buf
+}
+
+#[test]
+fn messages_round_trip() {
+    let mut rng = Rng(1);
+    for i in 0..CASES {
+        let len = rng.below(300);
+        let data = rng.bytes(len);
+        let bytes = encode_message(flag(i), &data);
+        let msg = FstpMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(msg.header.data_size as usize, data.len());
+        assert_eq!(msg.data.unwrap_or(&[]), &data[..]);
+        fuzz::fstp_message(&bytes);
+    }
+}
+
+#[test]
+fn file_metas_round_trip() {
+    let mut rng = Rng(2);
+    for _ in 0..CASES {
+        let meta = random_meta(&mut rng);
+        let bytes = encode_meta(&meta);
+        let (len, decoded) = FileMeta::from_bytes(&bytes).unwrap();
+        assert_eq!(len, bytes.len());
+        fuzz::assert_same_meta(&meta, &decoded);
+        fuzz::file_meta(&bytes);
+    }
+}
+
+#[test]
+fn consecutive_file_metas_decode_in_order() {
+    let mut rng = Rng(3);
+    let metas: Vec<FileMeta> = (0..20).map(|_| random_meta(&mut rng)).collect();
+    let bytes: Vec<u8> = metas.iter().flat_map(encode_meta).collect();
+    let mut rest = &bytes[..];
+    for meta in &metas {
+        let (len, decoded) = FileMeta::from_bytes(rest).unwrap();
+        fuzz::assert_same_meta(meta, &decoded);
+        rest = &rest[len..];
+    }
+    assert!(rest.is_empty());
+}
+
+#[test]
+fn peer_lists_round_trip() {
+    let mut rng = Rng(4);
+    for _ in 0..CASES {
+        let peers = random_peers(&mut rng);
+        let bytes = encode_peers(&peers);
+        let decoded = PeersWithFile::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.n_blocks, peers.n_blocks);
+        assert_eq!(decoded.peers_with_file, peers.peers_with_file);
+        assert_eq!(decoded.peers_with_blocks, peers.peers_with_blocks);
+        fuzz::peers_with_file(&bytes);
+    }
+}
+
+#[test]
+fn large_values_grow_the_buffer() {
+    // Mais peers e blocos do que cabiam nos antigos buffers fixos
+    let mut peers = PeersWithFile::new(2000);
+    for i in 0..1000u16 {
+        let addr = SocketAddr::from(([10, 0, (i >> 8) as u8, i as u8], i));
+        peers.peers_with_file.insert(addr);
+        peers
+            .peers_with_blocks
+            .insert(i as u32 * 2, HashSet::from([addr]));
+    }
+    let bytes = encode_peers(&peers);
+    assert_eq!(bytes.len(), 2 + 1000 * 6 + 2000 * 4 + 1000 * 6);
+    let decoded = PeersWithFile::from_bytes(&bytes).unwrap();
+    assert_eq!(decoded.peers_with_blocks, peers.peers_with_blocks);
+
+    let name = "x".repeat(5000);
+    let meta = FileMeta::full(name, 1 << 30, 4096);
+    let (len, decoded) = FileMeta::decode(&encode_meta(&meta)).unwrap();
+    assert_eq!(len, meta.encoded_len());
+    fuzz::assert_same_meta(&meta, &decoded);
+
+    // `encode` acrescenta; com um erro o buffer fica como estava
+    let mut buf = vec![7];
+    let size = meta.encode(&mut buf).unwrap();
+    assert_eq!(buf.len(), 1 + size);
+    let data = vec![0u8; u16::MAX as usize + 1];
+    assert!(FstpMessage::new(Flag::Add, &data).is_err());
+    let mut bad = meta.clone();
+    bad.name_len += 1;
+    assert!(bad.encode(&mut buf).is_err());
+    assert_eq!(buf.len(), 1 + size);
+}
+
+#[test]
+fn loads_round_trip() {
+    let mut rng = Rng(5);
+    for _ in 0..CASES {
+        let load = NodeLoad {
+            active_uploads: rng.next() as u32,
+            queued_requests: rng.next() as u32,
+            throughput: rng.next(),
+        };
+        let bytes = load.to_bytes();
+        assert_eq!(NodeLoad::from_bytes(&bytes).unwrap(), load);
+        fuzz::node_load(&bytes);
+    }
+}
+
+#[test]
+fn truncated_input_is_an_error() {
+    let mut rng = Rng(6);
+    for i in 0..50 {
+        let len = 1 + rng.below(100);
+        let data = rng.bytes(len);
+        let bytes = encode_message(flag(i), &data);
+        for len in 0..bytes.len() {
+            assert!(FstpMessage::from_bytes(&bytes[..len]).is_err());
+        }
+
+        let bytes = encode_meta(&random_meta(&mut rng));
+        for len in 0..bytes.len() {
+            assert!(FileMeta::from_bytes(&bytes[..len]).is_err());
+        }
+
+        // Um prefixo que acabe entre blocos é uma lista válida, mais curta
+        let bytes = encode_peers(&random_peers(&mut rng));
+        for len in 0..bytes.len() {
+            fuzz::peers_with_file(&bytes[..len]);
+        }
+        assert!(PeersWithFile::from_bytes(&bytes[..1]).is_err());
+    }
+}
+
+#[test]
+fn hostile_lengths_are_rejected() {
+    // data_size maior do que a mensagem
+    assert!(FstpMessage::from_bytes(&[2, 0xff, 0xff, 1, 2, 3]).is_err());
+    assert!(FstpMessage::from_bytes(&[9, 0, 0]).is_err());
+
+    let meta = FileMeta::full(String::from("a.bin"), 1000, 256);
+    let bytes = encode_meta(&meta);
+    // blocks_len enorme, sem o bitmap correspondente
+    let mut huge = bytes.clone();
+    huge[13..17].copy_from_slice(&u32::MAX.to_be_bytes());
+    assert!(FileMeta::from_bytes(&huge).is_err());
+    // name_len para além do fim
+    let mut long_name = bytes.clone();
+    long_name[17..19].copy_from_slice(&1000u16.to_be_bytes());
+    assert!(FileMeta::from_bytes(&long_name).is_err());
+    let mut flag = bytes.clone();
+    flag[8] = 7;
+    assert!(FileMeta::from_bytes(&flag).is_err());
+    let mut utf8 = bytes.clone();
+    let last = utf8.len() - 1;
+    utf8[last] = 0xff;
+    assert!(FileMeta::from_bytes(&utf8).is_err());
+
+    // Mais peers anunciados do que os bytes que os descrevem
+    assert!(PeersWithFile::from_bytes(&[0x03, 0xe8, 127, 0, 0, 1]).is_err());
+    let many_in_block = [0, 0, 0xff, 0xff, 0xff, 0xff];
+    assert!(PeersWithFile::from_bytes(&many_in_block).is_err());
+    assert!(NodeLoad::from_bytes(&[0; 15]).is_err());
+
+    // Respostas de peers: o len é verificado antes de ser alocado
+    let huge_block = [2, 0xff, 0xff, 0xff, 0xff];
+    let err = Response::read_from(&mut &huge_block[..]).unwrap_err();
+    assert!(err.to_string().starts_with("Response too large"), "{}", err);
+    let short_block = [2, 0, 0, 0, 10, 1, 2, 3];
+    assert!(Response::read_from(&mut &short_block[..]).is_err());
+    let pong_with_data = [3, 0, 0, 0, 1, 0];
+    assert!(Response::read_from(&mut &pong_with_data[..]).is_err());
+}
+
+#[test]
+fn arbitrary_bytes_never_panic() {
+    let mut rng = Rng(7);
+    let valid = [
+        encode_message(Flag::Add, &encode_meta(&random_meta(&mut rng))),
+        encode_meta(&random_meta(&mut rng)),
+        encode_peers(&random_peers(&mut rng)),
+        NodeLoad::default().to_bytes().to_vec(),
+    ];
+    for i in 0..20 * CASES {
+        // Bytes ao acaso, ou uma codificação válida com alguns estragados
+        let bytes = if i % 2 == 0 {
+            let len = rng.below(64);
+            rng.bytes(len)
+        } else {
+            let mut bytes = valid[rng.below(valid.len())].clone();
+            for _ in 0..1 + rng.below(4) {
+                let pos = rng.below(bytes.len());
+                bytes[pos] = rng.next() as u8;
+            }
+            bytes.truncate(rng.below(bytes.len() + 1));
+            bytes
+        };
+        fuzz::fstp_message(&bytes);
+        fuzz::file_meta(&bytes);
+        fuzz::peers_with_file(&bytes);
+        fuzz::node_load(&bytes);
+    }
+}
+
+#[test]
+fn errors_name_the_problem() {
+    assert_eq!(
+        FstpMessage::from_bytes(&[9, 0, 0]).unwrap_err(),
+        CodecError::InvalidFlag(9)
+    );
+    assert_eq!(
+        FstpMessage::from_bytes(&[2, 0, 4, 1]).unwrap_err(),
+        CodecError::Truncated("data")
+    );
+    assert_eq!(
+        NodeLoad::from_bytes(&[0; 15]).unwrap_err(),
+        CodecError::Truncated("throughput")
+    );
+
+    let mut bytes = encode_meta(&FileMeta::full(String::from("a.bin"), 10, 4));
+    bytes[8] = 2;
+    assert_eq!(
+        FileMeta::from_bytes(&bytes).unwrap_err(),
+        CodecError::InvalidValue {
+            field: "has_full_file",
+            value: 2
+        }
+    );
+
+    let list = |data_size| FstpMessage {
+        header: FstpHeader {
+            flag: Flag::List,
+            data_size,
+        },
+        data: Some(b"a.bin"),
+    };
+    assert_eq!(
+        list(4).as_bytes(&mut [0u8; 8]).unwrap_err(),
+        CodecError::InvalidValue {
+            field: "data_size",
+            value: 4
+        }
+    );
+    assert_eq!(
+        list(5).as_bytes(&mut [0u8; 3]).unwrap_err(),
+        CodecError::BufferTooSmall {
+            needed: 8,
+            available: 3
+        }
+    );
+}
+
+#[test]
+fn tracker_closes_connections_with_malformed_messages() {
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let tracker = TrackerServer::bind(addr, TrackerConfig::default())
+        .unwrap()
+        .spawn();
+    let mut client = TrackerClient::connect(tracker.local_addr()).unwrap();
+    client
+        .announce(&[FileMeta::full(String::from("a.bin"), 1000, 256)])
+        .unwrap();
+
+    let mut meta = encode_meta(&FileMeta::full(String::from("b.bin"), 10, 4));
+    meta.truncate(meta.len() - 2);
+    let malformed = [
+        encode_message(Flag::Add, &meta),
+        encode_message(Flag::File, &[0xff, 0xfe]),
+        encode_message(Flag::Stats, &[1, 2, 3]),
+        encode_message(Flag::Port, &[1]),
+    ];
+    for msg in &malformed {
+        let mut raw = TcpStream::connect(tracker.local_addr()).unwrap();
+        raw.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        raw.write_all(msg).unwrap();
+        // Nem o pedido de ficheiro fica à espera de uma resposta
+        raw.write_all(&encode_message(Flag::List, &[])).unwrap();
+        let mut rest = Vec::new();
+        raw.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty(), "answered {:?}", rest);
+    }
+
+    // As outras ligações continuam, e o anúncio estragado não entrou
+    assert_eq!(client.list().unwrap(), ["a.bin"]);
+    let files: usize = tracker
+        .state()
+        .nodes()
+        .iter()
+        .map(|(_, n)| n.files.len())
+        .sum();
+    assert_eq!(files, 1);
+    tracker.shutdown().unwrap();
+}
+
+#[test]
+fn tracker_serves_files_with_many_blocks() {
+    // Bem mais blocos do que cabiam nos buffers fixos do início (~250)
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let tracker = TrackerServer::bind(addr, TrackerConfig::default())
+        .unwrap()
+        .spawn();
+    let mut seeder = TrackerClient::connect(tracker.local_addr()).unwrap();
+    let mut leecher = TrackerClient::connect(tracker.local_addr()).unwrap();
+    let full = FileMeta::full(String::from("big.bin"), 1 << 30, 1 << 18);
+    let mut partial = full.clone();
+    partial.has_full_file = false;
+    partial.blocks = (0..full.blocks_len).map(|b| b % 2 == 0).collect();
+    seeder.announce(&[full]).unwrap();
+    leecher.set_port(9001).unwrap();
+    leecher.announce(&[partial]).unwrap();
+
+    let mut client = TrackerClient::connect(tracker.local_addr()).unwrap();
+    let started = std::time::Instant::now();
+    while tracker.state().holders("big.bin").len() < 2 {
+        assert!(started.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let peers = client.locate("big.bin").unwrap();
+    assert_eq!(peers.n_blocks, 4096);
+    assert_eq!(peers.peers_with_file.len(), 1);
+    assert_eq!(peers.peers_with_blocks.len(), 2048);
+    assert!(peers.peers_with_blocks.contains_key(&4094));
+    assert!(!peers.peers_with_blocks.contains_key(&4095));
+    tracker.shutdown().unwrap();
+}
+
+#[test]
+fn replies_too_large_for_a_message_keep_the_connection() {
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let tracker = TrackerServer::bind(addr, TrackerConfig::default())
+        .unwrap()
+        .spawn();
+    let mut client = TrackerClient::connect(tracker.local_addr()).unwrap();
+    // 4 bytes por bloco na resposta: mais de 16k blocos não cabem
+    let huge = FileMeta::full(String::from("huge.bin"), 20_000 << 18, 1 << 18);
+    let small = FileMeta::full(String::from("a.bin"), 1000, 256);
+    client.announce(&[huge, small]).unwrap();
+
+    let err = client.locate("huge.bin").unwrap_err();
+    assert!(matches!(err, TrackerError::Refused(_)), "{}", err);
+    // Um pedido sem nome também tem resposta
+    let err = client.locate("").unwrap_err();
+    assert!(err.to_string().ends_with("missing file name"), "{}", err);
+    assert!(!err.is_disconnect());
+    // A mesma ligação continua a funcionar, com os ficheiros anunciados
+    let mut names = client.list().unwrap();
+    names.sort();
+    assert_eq!(names, ["a.bin", "huge.bin"]);
+    assert_eq!(tracker.state().holders("a.bin").len(), 1);
+    tracker.shutdown().unwrap();
+}
+
+#[test]
+fn tracker_ranks_nodes_reporting_huge_loads() {
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let tracker = TrackerServer::bind(addr, TrackerConfig::default())
+        .unwrap()
+        .spawn();
+    let mut seeder = TrackerClient::connect(tracker.local_addr()).unwrap();
+    seeder
+        .announce(&[FileMeta::full(String::from("a.bin"), 1000, 256)])
+        .unwrap();
+    let load = NodeLoad {
+        active_uploads: u32::MAX,
+        queued_requests: u32::MAX,
+        throughput: u64::MAX,
+    };
+    seeder.report_load(&load).unwrap();
+
+    let score = || {
+        let nodes = tracker.state().nodes();
+        nodes
+            .iter()
+            .map(|(_, n)| n.load_score())
+            .fold(0.0, f64::max)
+    };
+    let started = std::time::Instant::now();
+    while score() == 0.0 {
+        assert!(started.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(score() > 0.99 && score() <= 1.0, "{}", score());
+
+    // O tracker continua a responder, com o nó na lista
+    let mut client = TrackerClient::connect(tracker.local_addr()).unwrap();
+    let peers = client.locate("a.bin").unwrap();
+    assert_eq!(peers.peers_with_file.len(), 1);
+    tracker.shutdown().unwrap();
+}
+
+#[test]
+fn pipelined_requests_are_all_answered() {
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let tracker = TrackerServer::bind(addr, TrackerConfig::default())
+        .unwrap()
+        .spawn();
+    let mut client = TrackerClient::connect(tracker.local_addr()).unwrap();
+    client
+        .announce(&[FileMeta::full(String::from("a.bin"), 1000, 256)])
+        .unwrap();
+
+    // Muitos pedidos sem ler as respostas: o tracker deixa de ler a
+    // ligação (e o envio bloqueia) em vez de os guardar todos
+    const REQUESTS: usize = 100_000;
+    let mut raw = TcpStream::connect(tracker.local_addr()).unwrap();
+    raw.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut writer = raw.try_clone().unwrap();
+    let sender = std::thread::spawn(move || {
+        let list = encode_message(Flag::List, &[]);
+        for _ in 0..REQUESTS {
+            writer.write_all(&list).unwrap();
+        }
+    });
+    std::thread::sleep(Duration::from_millis(200));
+    // Os outros nós continuam a ser atendidos
+    assert_eq!(client.list().unwrap(), ["a.bin"]);
+
+    let reply = encode_message(Flag::Ok, b"a.bin");
+    let mut buf = vec![0u8; reply.len()];
+    for _ in 0..REQUESTS {
+        raw.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, reply);
+    }
+    sender.join().unwrap();
+    tracker.shutdown().unwrap();
+}
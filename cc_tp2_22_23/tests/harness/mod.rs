@@ -0,0 +1,242 @@
+//! Rede de teste em loopback: o tracker corre numa thread do teste e
+//! cada nó é um processo `node` em modo daemon, com o seu diretório
+//! partilhado e a sua porta, comandado pelo socket de controlo.
+//!
+//! Os nós são indicados pelo índice devolvido por `add_node`; `kill` e
+//! `start` permitem parar e voltar a lançar um nó a meio de um cenário.
+#![allow(dead_code)]
+use local::config::TrackerConfig;
+use local::tracker_client::TrackerClient;
+use local::tracker_server::{RunningTracker, TrackerServer};
+use local::tracker_state::TrackerState;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{self, Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Tempo máximo de espera por um nó ou por uma condição.
+pub const TIMEOUT: Duration = Duration::from_secs(20);
+
+// Diretórios de clusters do mesmo processo de teste
+static CLUSTERS: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Cluster {
+    tracker: Option<RunningTracker>,
+    root: PathBuf,
+    nodes: Vec<Node>,
+}
+
+struct Node {
+    dir: PathBuf,
+    child: Option<Child>,
+}
+
+impl Node {
+    fn socket(&self) -> PathBuf {
+        self.dir.join("node.sock")
+    }
+
+    fn log(&self) -> String {
+        fs::read_to_string(self.dir.join("node.log")).unwrap_or_default()
+    }
+}
+
+impl Cluster {
+    /// Um tracker numa porta livre, ainda sem nós.
+    pub fn new() -> Self {
+        let root = std::env::temp_dir().join(format!(
+            "cc-cluster-{}-{}",
+            process::id(),
+            CLUSTERS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let server = TrackerServer::bind(addr, TrackerConfig::default())
+            .expect("tracker should bind");
+        Cluster {
+            tracker: Some(server.spawn()),
+            root,
+            nodes: Vec::new(),
+        }
+    }
+
+    fn tracker(&self) -> &RunningTracker {
+        self.tracker.as_ref().unwrap()
+    }
+
+    pub fn tracker_addr(&self) -> SocketAddr {
+        self.tracker().local_addr()
+    }
+
+    pub fn state(&self) -> &TrackerState {
+        self.tracker().state()
+    }
+
+    /// Nova ligação ao tracker, como a de um nó sem ficheiros.
+    pub fn client(&self) -> TrackerClient {
+        TrackerClient::connect(self.tracker_addr()).unwrap()
+    }
+
+    /// Cria um nó com `files` no diretório partilhado (os nomes podem ter
+    /// subdiretórios) e lança-o.
+    pub fn add_node(&mut self, files: &[(&str, &[u8])]) -> usize {
+        self.add_node_with(files, &[], "")
+    }
+
+    /// Como `add_node`, com ficheiros fora do diretório partilhado
+    /// (`other`, relativos ao diretório do nó) e mais linhas de
+    /// configuração.
+    pub fn add_node_with(
+        &mut self,
+        files: &[(&str, &[u8])],
+        other: &[(&str, &[u8])],
+        config: &str,
+    ) -> usize {
+        let i = self.nodes.len();
+        let dir = self.root.join(format!("node{}", i));
+        let shared = dir.join("shared");
+        fs::create_dir_all(&shared).unwrap();
+        let files = files.iter().map(|(name, data)| (shared.join(name), data));
+        let other = other.iter().map(|(name, data)| (dir.join(name), data));
+        for (path, data) in files.chain(other) {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+        let config =
+            format!("shared = {}\nport = 0\n{}", shared.display(), config);
+        fs::write(dir.join("node.config"), config).unwrap();
+        self.nodes.push(Node { dir, child: None });
+        self.start(i);
+        i
+    }
+
+    /// Lança o nó `i` (outra vez, depois de `kill`), com os ficheiros que
+    /// tiver no disco, e espera pelo socket de controlo.
+    pub fn start(&mut self, i: usize) {
+        let tracker = self.tracker_addr().to_string();
+        let node = &mut self.nodes[i];
+        assert!(node.child.is_none(), "node {} is already running", i);
+        let log = File::create(node.dir.join("node.log")).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_node"))
+            .args([tracker.as_str(), "daemon", "--foreground"])
+            .current_dir(&node.dir)
+            .stdin(Stdio::null())
+            .stdout(log.try_clone().unwrap())
+            .stderr(log)
+            .spawn()
+            .expect("node should start");
+        node.child = Some(child);
+
+        let started = Instant::now();
+        while UnixStream::connect(node.socket()).is_err() {
+            let child = node.child.as_mut().unwrap();
+            if let Some(status) = child.try_wait().unwrap() {
+                node.child = None;
+                panic!("node {} exited ({}):\n{}", i, status, node.log());
+            }
+            if started.elapsed() > TIMEOUT {
+                panic!("node {} didn't start:\n{}", i, node.log());
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    /// Mata o nó `i`, sem sair da rede de forma ordenada.
+    pub fn kill(&mut self, i: usize) {
+        let mut child = self.nodes[i]
+            .child
+            .take()
+            .unwrap_or_else(|| panic!("node {} isn't running", i));
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+
+    /// Manda um comando da shell ao nó `i`: a saída, ou a mensagem de erro.
+    pub fn ctl(&self, i: usize, command: &str) -> Result<String, String> {
+        let mut conn = UnixStream::connect(self.nodes[i].socket())
+            .unwrap_or_else(|e| panic!("node {} control socket: {}", i, e));
+        conn.set_read_timeout(Some(TIMEOUT)).unwrap();
+        writeln!(conn, "{}", command).unwrap();
+        let mut resp = String::new();
+        conn.read_to_string(&mut resp).unwrap();
+        match resp.split_once('\n') {
+            Some(("ok", out)) => Ok(out.to_string()),
+            Some(("error", msg)) => Err(msg.trim_end().to_string()),
+            _ => panic!("node {}: invalid control response {:?}", i, resp),
+        }
+    }
+
+    pub fn shared(&self, i: usize) -> PathBuf {
+        self.nodes[i].dir.join("shared")
+    }
+
+    /// Diretório do nó `i`, onde estão a configuração e as outras raízes.
+    pub fn dir(&self, i: usize) -> PathBuf {
+        self.nodes[i].dir.clone()
+    }
+
+    /// Onde o nó `i` serve blocos, como o tracker o indica aos peers.
+    pub fn addr(&self, i: usize) -> SocketAddr {
+        let status = self.ctl(i, "status").unwrap();
+        let port = status
+            .lines()
+            .find_map(|l| l.strip_prefix("port"))
+            .and_then(|p| p.trim().parse::<u16>().ok())
+            .unwrap_or_else(|| panic!("no port in status:\n{}", status));
+        SocketAddr::new([127, 0, 0, 1].into(), port)
+    }
+
+    /// Nós (pelo endereço de blocos) que o tracker dá como tendo `name`,
+    /// e se têm o ficheiro completo.
+    pub fn holders(&self, name: &str) -> Vec<(SocketAddr, bool)> {
+        let mut holders: Vec<_> = self
+            .state()
+            .holders(name)
+            .into_iter()
+            .map(|h| (h.addr, h.meta.has_full_file))
+            .collect();
+        holders.sort();
+        holders
+    }
+
+    pub fn log(&self, i: usize) -> String {
+        self.nodes[i].log()
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for node in &mut self.nodes {
+            if let Some(mut child) = node.child.take() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+        if let Some(tracker) = self.tracker.take() {
+            let _ = tracker.shutdown();
+        }
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Espera até `cond` se verificar; falha o teste ao fim de `TIMEOUT`.
+pub fn wait_until(what: &str, mut cond: impl FnMut() -> bool) {
+    let started = Instant::now();
+    while !cond() {
+        if started.elapsed() > TIMEOUT {
+            panic!("timed out waiting for {}", what);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Conteúdo de teste, diferente para cada `seed`.
+pub fn payload(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8 ^ seed).collect()
+}
@@ -0,0 +1,204 @@
+use local::congestion::Algorithm;
+use local::reliable::{Config, ReliableSocket};
+use std::thread;
+use std::time::Duration;
+
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+fn lossy(loss_rate: f64) -> Config {
+    Config {
+        min_rto: Duration::from_millis(20),
+        initial_rto: Duration::from_millis(100),
+        max_retries: 20,
+        loss_rate,
+        ..Config::default()
+    }
+}
+
+#[test]
+fn delivers_large_message() {
+    let a = ReliableSocket::bind("127.0.0.1:0", Config::default()).unwrap();
+    let b = ReliableSocket::bind("127.0.0.1:0", Config::default()).unwrap();
+    let data = payload(1024 * 1024);
+
+    a.send_to(&data, b.local_addr().unwrap()).unwrap();
+    let (recv, from) = b.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    assert_eq!(recv, data);
+    assert_eq!(from, a.local_addr().unwrap());
+}
+
+#[test]
+fn delivers_empty_message() {
+    let a = ReliableSocket::bind("127.0.0.1:0", Config::default()).unwrap();
+    let b = ReliableSocket::bind("127.0.0.1:0", Config::default()).unwrap();
+
+    a.send_to(&[], b.local_addr().unwrap()).unwrap();
+    let (recv, _) = b.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    assert!(recv.is_empty());
+}
+
+#[test]
+fn recovers_from_injected_loss() {
+    let a = ReliableSocket::bind("127.0.0.1:0", lossy(0.2)).unwrap();
+    let b = ReliableSocket::bind("127.0.0.1:0", lossy(0.2)).unwrap();
+    let data = payload(300 * 1024);
+
+    a.send_to(&data, b.local_addr().unwrap()).unwrap();
+    let (recv, _) = b.recv_timeout(Duration::from_secs(10)).unwrap();
+
+    assert_eq!(recv, data);
+    let (_, stats) = &a.link_stats()[0];
+    assert!(stats.retransmitted > 0);
+    // Só os fragmentos em falta são reenviados
+    assert!(stats.retransmitted < stats.sent / 2);
+    assert!(stats.srtt.is_some());
+}
+
+#[test]
+fn request_response_over_lossy_link() {
+    let server = ReliableSocket::bind("127.0.0.1:0", lossy(0.1)).unwrap();
+    let client = ReliableSocket::bind("127.0.0.1:0", lossy(0.1)).unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let handle = thread::spawn(move || {
+        for _ in 0..5 {
+            let (req, from) = server.recv_from().unwrap();
+            let resp: Vec<u8> = req.iter().rev().copied().collect();
+            server.send_to(&resp, from).unwrap();
+        }
+    });
+
+    for i in 0..5 {
+        let req = payload(10_000 * (i + 1));
+        client.send_to(&req, server_addr).unwrap();
+        let (resp, from) =
+            client.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(from, server_addr);
+        assert_eq!(resp, req.iter().rev().copied().collect::<Vec<_>>());
+    }
+    handle.join().unwrap();
+}
+
+#[test]
+fn congestion_window_follows_losses() {
+    let clean = ReliableSocket::bind("127.0.0.1:0", Config::default()).unwrap();
+    let a = ReliableSocket::bind("127.0.0.1:0", lossy(0.2)).unwrap();
+    let b = ReliableSocket::bind("127.0.0.1:0", lossy(0.2)).unwrap();
+    let data = payload(200 * 1024);
+
+    clean.send_to(&data, b.local_addr().unwrap()).unwrap();
+    a.send_to(&data, b.local_addr().unwrap()).unwrap();
+
+    let window = Config::default().window;
+    assert_eq!(clean.link_stats()[0].1.cwnd, window);
+    assert!(a.link_stats()[0].1.cwnd < window);
+}
+
+#[test]
+fn fixed_window_ignores_losses() {
+    let config = Config {
+        congestion: Algorithm::Fixed,
+        window: 16,
+        ..lossy(0.2)
+    };
+    let a = ReliableSocket::bind("127.0.0.1:0", config).unwrap();
+    let b = ReliableSocket::bind("127.0.0.1:0", lossy(0.2)).unwrap();
+
+    a.send_to(&payload(100 * 1024), b.local_addr().unwrap())
+        .unwrap();
+
+    let (_, stats) = &a.link_stats()[0];
+    assert!(stats.retransmitted > 0);
+    assert_eq!(stats.cwnd, 16);
+}
+
+#[test]
+fn gives_up_without_receiver() {
+    let config = Config {
+        initial_rto: Duration::from_millis(10),
+        min_rto: Duration::from_millis(10),
+        max_rto: Duration::from_millis(20),
+        max_retries: 3,
+        ..Config::default()
+    };
+    let a = ReliableSocket::bind("127.0.0.1:0", config).unwrap();
+    // Socket sem ReliableSocket: recebe os dados mas nunca confirma
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let err = a
+        .send_to(b"hello", silent.local_addr().unwrap())
+        .unwrap_err();
+
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn ignores_acks_ahead_of_the_window() {
+    let config = Config {
+        congestion: Algorithm::Fixed,
+        window: 2,
+        fragment_size: 100,
+        ..lossy(0.0)
+    };
+    let a = ReliableSocket::bind("127.0.0.1:0", config).unwrap();
+    // Recetor feito à mão, para poder mandar ACKs falsos
+    let b = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    b.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let to = b.local_addr().unwrap();
+    let sender = thread::spawn(move || a.send_to(&payload(1000), to));
+
+    let ack = |msg_id: &[u8], cum: u32, from| {
+        let mut packet = vec![2];
+        packet.extend_from_slice(msg_id);
+        packet.extend_from_slice(&cum.to_be_bytes());
+        packet.extend_from_slice(&0u16.to_be_bytes());
+        b.send_to(&packet, from).unwrap();
+    };
+    let mut received = [false; 10];
+    let mut forged = false;
+    let mut buf = [0u8; 2048];
+    loop {
+        let (n, from) = b.recv_from(&mut buf).unwrap();
+        assert!(n >= 13 && buf[0] == 1);
+        let msg_id = buf[1..5].to_vec();
+        let seq = u32::from_be_bytes(buf[5..9].try_into().unwrap());
+        received[seq as usize] = true;
+        if !forged {
+            // Só 2 fragmentos foram enviados: confirmar 9 é impossível
+            ack(&msg_id, 9, from);
+            forged = true;
+        }
+        let cum = received.iter().take_while(|r| **r).count() as u32;
+        ack(&msg_id, cum, from);
+        if cum == 10 {
+            break;
+        }
+    }
+    sender.join().unwrap().unwrap();
+}
+
+#[test]
+fn ignores_messages_above_the_limit() {
+    let config = Config {
+        max_message: 64 * 1024,
+        ..Config::default()
+    };
+    let a = ReliableSocket::bind("127.0.0.1:0", Config::default()).unwrap();
+    let b = ReliableSocket::bind("127.0.0.1:0", config).unwrap();
+    let to = b.local_addr().unwrap();
+
+    let err = a
+        .send_timeout(&payload(100 * 1024), to, Duration::from_secs(1))
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+    // As mais pequenas continuam a chegar
+    let data = payload(60 * 1024);
+    a.send_to(&data, to).unwrap();
+    let (recv, _) = b.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(recv, data);
+}